@@ -0,0 +1,138 @@
+// Antialiasing for filled triangles. Each cell is split into a grid of samples and triangles are
+// drawn into a buffer with one entry per sample (really just a Tile that's that many times bigger
+// than the screen). Each cell then gets the average of its samples, so a cell half covered by a
+// triangle comes out half as bright, and that's what picks its character.
+//
+// The two kinds only differ in how often things are shaded. Supersampling shades every sample, and
+// multisampling shades each face once per cell and copies that to every sample in the cell it
+// covers, which is much cheaper and still gets the edges right.
+
+use crate::device::*;
+use crate::raster::*;
+use crate::render_objects::*;
+use crate::stats::*;
+use crate::target::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Antialiasing {
+    Off,
+    // Each cell is n by n samples.
+    Supersample(usize),
+    Multisample(usize),
+}
+
+impl Antialiasing {
+    // How many samples across (and down) each cell there are.
+    pub fn samples(self) -> usize {
+        match self {
+            Antialiasing::Off => 1,
+            Antialiasing::Supersample(n) | Antialiasing::Multisample(n) => n.max(1),
+        }
+    }
+}
+
+// From screen coordinates to the sample buffer's, where n is samples across each cell. Samples
+// are spread evenly over the cell, so the cell's own centre doesn't move.
+pub fn to_samples(p: (f64, f64), n: usize) -> (f64, f64) {
+    let n = n as f64;
+
+    ((p.0 + 0.5) * n - 0.5, (p.1 + 0.5) * n - 0.5)
+}
+
+impl Device {
+    // A sample buffer for the screen with n by n samples per cell. Every sample starts off as
+    // whatever's in its cell, but not drawn, so resolve can tell what's new.
+    pub fn sample_tile(&self, n: usize) -> Tile {
+        let (width, height) = self.dimensions;
+        let size = width * n * height * n;
+        let mut tile = Tile {
+            viewport: (width * n, height * n),
+            rect: (0, 0, width * n, height * n),
+            pixels: Vec::with_capacity(size),
+            drawn: vec![false; size],
+            depth: Vec::with_capacity(size),
+            ids: Vec::with_capacity(size),
+            stats: FrameStats::new(),
+        };
+
+        for sy in 0..height * n {
+            for sx in 0..width * n {
+                let i = (sy / n) * width + sx / n;
+
+                tile.pixels.push(self.pixels[i].clone());
+                tile.depth.push(self.depth[i]);
+                tile.ids.push(self.ids[i]);
+            }
+        }
+
+        tile
+    }
+
+    // Average each cell's samples back into the framebuffer. The depth and ID of a cell come from
+    // its nearest sample. The sample buffer is kept in sample_buffer afterwards, for anything that
+    // works on cells after the triangles are drawn (like shade_cells) to look at.
+    pub fn resolve(&mut self, tile: Tile) {
+        let (width, height) = self.dimensions;
+        let n = tile.viewport.0 / width.max(1);
+        let row = width * n;
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let samples: Vec<usize> = (0..n * n).map(|k| (y * n + k / n) * row + x * n + k % n).collect();
+                let mut nearest: Option<usize> = None;
+
+                if !samples.iter().any(|s| tile.drawn[*s]) {
+                    continue;
+                }
+
+                for s in samples.iter().copied() {
+                    if nearest.is_none_or(|j| tile.depth[s] < tile.depth[j]) {
+                        nearest = Some(s);
+                    }
+                }
+
+                self.pixels[i] = Colour::average(samples.iter().map(|s| tile.pixels[*s].clone())).unwrap();
+                self.drawn[i] = true;
+
+                if let Some(s) = nearest {
+                    self.depth[i] = tile.depth[s];
+                    self.ids[i] = tile.ids[s];
+                }
+            }
+        }
+
+        // Counted per sample rather than per cell.
+        self.stats.pixels_shaded += tile.stats.pixels_shaded;
+        self.stats.overdraw += tile.stats.overdraw;
+        self.stats.triangles_submitted += tile.stats.triangles_submitted;
+        self.stats.triangles_culled += tile.stats.triangles_culled;
+        self.stats.triangles_clipped += tile.stats.triangles_clipped;
+        self.sample_buffer = Some(tile);
+    }
+
+    // Draw filled triangles, antialiased however antialiasing says.
+    pub fn draw_filled(&mut self, triangles: &[ScreenTriangle]) {
+        let n = self.antialiasing.samples();
+
+        if self.antialiasing == Antialiasing::Off {
+            self.draw_triangles(triangles, false);
+            return;
+        }
+
+        // Every triangle is one colour, so there's nothing to shade and both kinds are the same.
+        // The sample buffer isn't split into bins, so this ignores threads.
+        let mut tile = self.sample_tile(n);
+
+        for t in triangles {
+            let scaled = ScreenTriangle {
+                points: t.points.map(|p| to_samples(p, n)),
+                ..t.clone()
+            };
+
+            tile.draw_screen_triangle(&scaled, false);
+        }
+
+        self.resolve(tile);
+    }
+}
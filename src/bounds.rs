@@ -0,0 +1,205 @@
+// Bounding volumes: simple shapes that are guaranteed to contain a mesh, so that questions like
+// "can the camera see any of this?" can be answered without looking at every triangle.
+
+use crate::structures::*;
+
+// Axis-aligned bounding box, given by its two opposite corners.
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vector,
+    pub max: Vector,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingSphere {
+    pub centre: Vector,
+    pub radius: f64,
+}
+
+// The six planes around what the camera can see, facing inwards. Each plane is (a, b, c, d) with
+// a point p inside it when a*p.x + b*p.y + c*p.z + d >= 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    pub planes: [Vector; 6],
+}
+
+impl Aabb {
+    // A box with nothing in it. Adding any point to it gives a box around just that point.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector::point(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Vector::point(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vector]) -> Aabb {
+        let mut aabb = Aabb::empty();
+
+        for p in points {
+            aabb.add_point(p);
+        }
+
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn add_point(&mut self, p: &Vector) {
+        self.min = Vector::point(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Vector::point(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    // The smallest box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector::point(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vector::point(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.max.x >= other.min.x
+            && self.min.y <= other.max.y && self.max.y >= other.min.y
+            && self.min.z <= other.max.z && self.max.z >= other.min.z
+    }
+
+    pub fn centre(&self) -> Vector {
+        Vector::point((self.min.x + self.max.x) * 0.5, (self.min.y + self.max.y) * 0.5, (self.min.z + self.max.z) * 0.5)
+    }
+
+    pub fn size(&self) -> Vector {
+        Vector::direction(self.max.x - self.min.x, self.max.y - self.min.y, self.max.z - self.min.z)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let s = self.size();
+
+        2.0 * (s.x * s.y + s.y * s.z + s.z * s.x)
+    }
+
+    // 0, 1 or 2 for whichever of x, y and z the box is longest along.
+    pub fn longest_axis(&self) -> usize {
+        let s = self.size();
+
+        if s.x >= s.y && s.x >= s.z {
+            0
+        }
+
+        else if s.y >= s.z {
+            1
+        }
+
+        else {
+            2
+        }
+    }
+
+    pub fn corners(&self) -> [Vector; 8] {
+        let (a, b) = (self.min, self.max);
+
+        [
+            Vector::point(a.x, a.y, a.z), Vector::point(b.x, a.y, a.z),
+            Vector::point(a.x, b.y, a.z), Vector::point(b.x, b.y, a.z),
+            Vector::point(a.x, a.y, b.z), Vector::point(b.x, a.y, b.z),
+            Vector::point(a.x, b.y, b.z), Vector::point(b.x, b.y, b.z),
+        ]
+    }
+
+    // A box around this one after it's been moved by m. Rotating a box makes it poke out of its
+    // old shape, so the new box is usually a bit bigger than it strictly needs to be.
+    pub fn transformed(&self, m: &Matrix) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        let mut aabb = Aabb::empty();
+
+        for c in self.corners() {
+            aabb.add_point(&(m * c));
+        }
+
+        aabb
+    }
+}
+
+impl BoundingSphere {
+    // A sphere around the centre of the points' bounding box. Not the smallest sphere possible,
+    // but close enough and cheap to work out.
+    pub fn from_points(points: &[Vector]) -> BoundingSphere {
+        let centre = Aabb::from_points(points).centre();
+        let radius = points.iter().map(|p| Vector::distance(&centre, p)).fold(0.0, f64::max);
+
+        BoundingSphere {
+            centre,
+            radius,
+        }
+    }
+
+    // The sphere after being moved by m. Non-uniform scaling turns a sphere into an ellipsoid, so
+    // the radius is stretched by the largest scale to still cover it.
+    pub fn transformed(&self, m: &Matrix) -> BoundingSphere {
+        let scale = (0..3)
+            .map(|i| Vector::direction(m.vals[0][i], m.vals[1][i], m.vals[2][i]).magnitude())
+            .fold(0.0, f64::max);
+
+        BoundingSphere {
+            centre: m * self.centre,
+            radius: self.radius * scale,
+        }
+    }
+}
+
+impl Frustum {
+    // Pulls the planes straight out of a view-projection matrix (Gribb & Hartmann). Anything
+    // inside all of them ends up inside -w..w in clip space.
+    pub fn from_matrix(m: &Matrix) -> Frustum {
+        let row = |i: usize| Vector::from(m.vals[i][0], m.vals[i][1], m.vals[i][2], m.vals[i][3]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+
+        let mut planes = [w + x, w - x, w + y, w - y, w + z, w - z];
+
+        // Normalising them means plane_distance gives actual distances, which the sphere test
+        // needs.
+        for p in planes.iter_mut() {
+            let length = Vector::direction(p.x, p.y, p.z).magnitude();
+
+            if length > 0.0 {
+                *p = Vector::from(p.x / length, p.y / length, p.z / length, p.w / length);
+            }
+        }
+
+        Frustum {
+            planes,
+        }
+    }
+
+    fn plane_distance(plane: &Vector, p: &Vector) -> f64 {
+        plane.x * p.x + plane.y * p.y + plane.z * p.z + plane.w
+    }
+
+    // false only if the sphere is definitely out of view.
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|p| Frustum::plane_distance(p, &sphere.centre) >= -sphere.radius)
+    }
+
+    // false only if the box is definitely out of view. Boxes near the corners of the frustum can
+    // get through this when they're actually outside, which just means a bit of wasted work.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|p| {
+            // The corner of the box furthest along the plane's normal.
+            let corner = Vector::point(
+                if p.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if p.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if p.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+
+            Frustum::plane_distance(p, &corner) >= 0.0
+        })
+    }
+}
@@ -0,0 +1,550 @@
+// Bounding volume hierarchies. Things are grouped into a tree of boxes, so a ray (or a box) only
+// has to be checked against the few things in the boxes it actually goes through rather than
+// every single one. There are two levels: one over the faces of each piece of geometry, built
+// once when it's loaded, and one over the meshes in a scene, which can be refitted as they move.
+
+use crate::bounds::*;
+use crate::ray::*;
+use crate::render_objects::*;
+use crate::structures::*;
+
+// Nodes with this many things in them or fewer are never split.
+const MAX_LEAF_SIZE: usize = 4;
+// The SAH is allowed to decide it isn't worth splitting nodes up to this size.
+const MAX_SAH_LEAF_SIZE: usize = 16;
+// How many buckets things are sorted into along an axis when looking for the best SAH split.
+const SAH_BINS: usize = 12;
+
+// How to decide where to split a node in two.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitMethod {
+    // Half the things on each side, along the longest axis. Quick to build.
+    #[allow(dead_code)]
+    Median,
+    // Surface area heuristic: whichever split is cheapest to trace rays through on average.
+    // Slower to build, quicker to use.
+    Sah,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    // For a leaf, where its things start in Bvh::primitives. Otherwise the index of its second
+    // child; the first child always comes straight after it.
+    offset: usize,
+    // How many things are in a leaf. 0 for anything that isn't a leaf.
+    count: usize,
+}
+
+// A hierarchy over any list of things that have bounding boxes. It only deals in their indices,
+// so it's up to the caller what they are.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Indices of the things, in the order the leaves refer to them.
+    pub primitives: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new() -> Bvh {
+        Bvh::default()
+    }
+
+    // Builds a hierarchy over things with the given boxes.
+    pub fn build(bounds: &[Aabb], method: SplitMethod) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            primitives: (0..bounds.len()).collect(),
+        };
+
+        if !bounds.is_empty() {
+            let centres: Vec<Vector> = bounds.iter().map(|b| b.centre()).collect();
+
+            bvh.build_node(bounds, &centres, 0, bounds.len(), method);
+        }
+
+        bvh
+    }
+
+    // Adds a node for primitives[start..end], and everything under it. Returns its index.
+    fn build_node(&mut self, bounds: &[Aabb], centres: &[Vector], start: usize, end: usize, method: SplitMethod) -> usize {
+        let index = self.nodes.len();
+        let node_bounds = self.primitives[start..end].iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i]));
+
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            offset: start,
+            count: end - start,
+        });
+
+        if end - start <= MAX_LEAF_SIZE {
+            return index;
+        }
+
+        let mid = match method {
+            SplitMethod::Median => self.split_median(centres, start, end),
+            SplitMethod::Sah => match self.split_sah(bounds, centres, start, end) {
+                Some(mid) => mid,
+                None => return index,
+            },
+        };
+
+        self.build_node(bounds, centres, start, mid, method);
+        let second = self.build_node(bounds, centres, mid, end, method);
+
+        self.nodes[index].offset = second;
+        self.nodes[index].count = 0;
+
+        index
+    }
+
+    fn centre_bounds(&self, centres: &[Vector], start: usize, end: usize) -> Aabb {
+        let mut aabb = Aabb::empty();
+
+        for &i in &self.primitives[start..end] {
+            aabb.add_point(&centres[i]);
+        }
+
+        aabb
+    }
+
+    fn split_median(&mut self, centres: &[Vector], start: usize, end: usize) -> usize {
+        let axis = self.centre_bounds(centres, start, end).longest_axis();
+        let mid = (end - start) / 2;
+
+        self.primitives[start..end].select_nth_unstable_by(mid, |&a, &b| {
+            centres[a][axis].total_cmp(&centres[b][axis])
+        });
+
+        start + mid
+    }
+
+    // Sorts the things into buckets along the longest axis and tries splitting between each pair
+    // of buckets. The cost of a split is how likely a ray is to go into each side (their surface
+    // areas) times how many things it would then have to check. Gives back None if it would be
+    // cheaper to not split at all.
+    fn split_sah(&mut self, bounds: &[Aabb], centres: &[Vector], start: usize, end: usize) -> Option<usize> {
+        let centre_bounds = self.centre_bounds(centres, start, end);
+        let axis = centre_bounds.longest_axis();
+        let (min, extent) = (centre_bounds.min[axis], centre_bounds.size()[axis]);
+
+        // Everything's in the same place, so there's nothing to go on. Just cut the list in half.
+        if extent <= 0.0 {
+            return Some(self.split_median(centres, start, end));
+        }
+
+        let bin = |c: &Vector| (((c[axis] - min) / extent * SAH_BINS as f64) as usize).min(SAH_BINS - 1);
+
+        let mut counts = [0usize; SAH_BINS];
+        let mut boxes = [Aabb::empty(); SAH_BINS];
+
+        for &i in &self.primitives[start..end] {
+            let b = bin(&centres[i]);
+
+            counts[b] += 1;
+            boxes[b] = boxes[b].union(&bounds[i]);
+        }
+
+        let mut best = (f64::INFINITY, 0);
+
+        for split in 1..SAH_BINS {
+            let (left, right) = (&boxes[..split], &boxes[split..]);
+            let (left_count, right_count): (usize, usize) = (counts[..split].iter().sum(), counts[split..].iter().sum());
+
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let left_area = left.iter().fold(Aabb::empty(), |a, b| a.union(b)).surface_area();
+            let right_area = right.iter().fold(Aabb::empty(), |a, b| a.union(b)).surface_area();
+            let cost = left_area * left_count as f64 + right_area * right_count as f64;
+
+            if cost < best.0 {
+                best = (cost, split);
+            }
+        }
+
+        let count = end - start;
+        let area = self.primitives[start..end].iter().fold(Aabb::empty(), |b, &i| b.union(&bounds[i])).surface_area();
+
+        // Checking a node's box costs about as much as checking one thing in it.
+        let split_cost = 1.0 + best.0 / area.max(f64::MIN_POSITIVE);
+
+        // The centres being spread out means the first and last buckets both have something in
+        // them, so there's always a split to be had.
+        if count <= MAX_SAH_LEAF_SIZE && split_cost >= count as f64 {
+            return None;
+        }
+
+        // Move everything in the buckets before the split to the front.
+        let mut mid = start;
+
+        for i in start..end {
+            if bin(&centres[self.primitives[i]]) < best.1 {
+                self.primitives.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        Some(mid)
+    }
+
+    // For when the things have moved but the tree's shape is still good enough: recalculates
+    // every node's box from the new boxes of the things in it. Much quicker than rebuilding,
+    // though the tree gets worse the further things move from where they were.
+    pub fn refit(&mut self, bounds: &[Aabb]) {
+        // Children always come after their parents, so going backwards does them first.
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+
+            self.nodes[i].bounds = if node.count > 0 {
+                self.primitives[node.offset..node.offset + node.count]
+                    .iter()
+                    .fold(Aabb::empty(), |b, &p| b.union(&bounds[p]))
+            }
+
+            else {
+                self.nodes[i + 1].bounds.union(&self.nodes[node.offset].bounds)
+            };
+        }
+    }
+
+    // Finds the closest thing along a ray. hit is called with each thing the ray might hit and the
+    // distance of the closest hit so far, and should give back the distance and whatever else it
+    // likes if the ray hits it any closer than that.
+    pub fn intersect_ray<T, F>(&self, ray: &Ray, max_t: f64, mut hit: F) -> Option<T>
+    where
+        F: FnMut(usize, f64) -> Option<(f64, T)>,
+    {
+        let mut closest = None;
+        let mut max_t = max_t;
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            let node = self.nodes[i];
+
+            // Check again, since something closer might have been found since it was pushed.
+            if ray.intersect_aabb(&node.bounds, max_t).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &p in &self.primitives[node.offset..node.offset + node.count] {
+                    if let Some((t, value)) = hit(p, max_t) {
+                        if t < max_t {
+                            max_t = t;
+                            closest = Some(value);
+                        }
+                    }
+                }
+            }
+
+            else {
+                let first = (i + 1, ray.intersect_aabb(&self.nodes[i + 1].bounds, max_t));
+                let second = (node.offset, ray.intersect_aabb(&self.nodes[node.offset].bounds, max_t));
+
+                // Go into the nearer child first, so the further one can hopefully be skipped.
+                let (near, far) = if second.1.unwrap_or(f64::INFINITY) < first.1.unwrap_or(f64::INFINITY) {
+                    (second, first)
+                }
+
+                else {
+                    (first, second)
+                };
+
+                if far.1.is_some() {
+                    stack.push(far.0);
+                }
+
+                if near.1.is_some() {
+                    stack.push(near.0);
+                }
+            }
+        }
+
+        closest
+    }
+
+    // Calls f with every thing in a leaf whose box overlaps aabb. These are only the things that
+    // might overlap it; f still has to check.
+    pub fn query_box<F: FnMut(usize)>(&self, aabb: &Aabb, mut f: F) {
+        let mut stack = Vec::new();
+
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            let node = self.nodes[i];
+
+            if !node.bounds.intersects(aabb) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &p in &self.primitives[node.offset..node.offset + node.count] {
+                    f(p);
+                }
+            }
+
+            else {
+                stack.push(node.offset);
+                stack.push(i + 1);
+            }
+        }
+    }
+}
+
+impl Geometry {
+    pub fn face_bounds(&self) -> Vec<Aabb> {
+        self.faces.iter()
+            .map(|f| Aabb::from_points(&[self.vertices[f.vertices[0]], self.vertices[f.vertices[1]], self.vertices[f.vertices[2]]]))
+            .collect()
+    }
+
+    // The closest face a ray hits, in the geometry's own coordinates.
+    pub fn intersect_ray(&self, ray: &Ray, max_t: f64) -> Option<Hit> {
+        self.bvh.intersect_ray(ray, max_t, |i, max_t| {
+            let f = &self.faces[i];
+            let (t, barycentric) = ray.intersect_triangle(&self.vertices[f.vertices[0]], &self.vertices[f.vertices[1]], &self.vertices[f.vertices[2]])?;
+
+            if t < max_t {
+                Some((t, Hit { t, face: i, barycentric }))
+            }
+
+            else {
+                None
+            }
+        })
+    }
+
+    // Every face whose bounding box overlaps aabb.
+    #[allow(dead_code)]
+    pub fn faces_in_box(&self, aabb: &Aabb) -> Vec<usize> {
+        let mut faces = Vec::new();
+
+        self.bvh.query_box(aabb, |i| {
+            let f = &self.faces[i];
+            let bounds = Aabb::from_points(&[self.vertices[f.vertices[0]], self.vertices[f.vertices[1]], self.vertices[f.vertices[2]]]);
+
+            if bounds.intersects(aabb) {
+                faces.push(i);
+            }
+        });
+
+        faces
+    }
+}
+
+// A mesh placed in a SceneBvh.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub mesh: Mesh,
+    // The matrix it was placed with, e.g. its node's world matrix. Doesn't include the mesh's own
+    // model matrix.
+    pub world: Matrix,
+    // Takes world coordinates to the geometry's own coordinates. None if the mesh has been
+    // squashed flat and there's no way back.
+    inverse: Option<Matrix>,
+    bounds: Aabb,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SceneHit {
+    // Which of SceneBvh::instances was hit
+    pub instance: usize,
+    pub hit: Hit,
+}
+
+// The top level: a hierarchy over meshes, each of which has its own hierarchy over its faces.
+// Rays are moved into each mesh's own coordinates to be checked against its faces, so a mesh
+// moving only means refitting this and not rebuilding anything underneath it.
+#[derive(Debug, Clone, Default)]
+pub struct SceneBvh {
+    pub instances: Vec<Instance>,
+    bvh: Bvh,
+}
+
+impl Instance {
+    fn from(world: Matrix, mesh: Mesh) -> Instance {
+        let model = world * mesh.model_matrix();
+
+        Instance {
+            inverse: model.inverse(),
+            bounds: mesh.bounds(&model).0,
+            world,
+            mesh,
+        }
+    }
+}
+
+impl SceneBvh {
+    // Takes (world matrix, mesh) pairs, as device::instances gives them without the ids. The
+    // meshes are cloned, but that's cheap as they share their geometry.
+    pub fn build(meshes: &[(Matrix, &Mesh)]) -> SceneBvh {
+        let instances: Vec<Instance> = meshes.iter().map(|(world, m)| Instance::from(*world, (*m).clone())).collect();
+        let bounds: Vec<Aabb> = instances.iter().map(|i| i.bounds).collect();
+
+        SceneBvh {
+            bvh: Bvh::build(&bounds, SplitMethod::Sah),
+            instances,
+        }
+    }
+
+    // Move an instance. Call refit once everything that's moving has been moved.
+    #[allow(dead_code)]
+    pub fn set_world(&mut self, instance: usize, world: Matrix) {
+        let mesh = self.instances[instance].mesh.clone();
+
+        self.instances[instance] = Instance::from(world, mesh);
+    }
+
+    #[allow(dead_code)]
+    pub fn refit(&mut self) {
+        let bounds: Vec<Aabb> = self.instances.iter().map(|i| i.bounds).collect();
+
+        self.bvh.refit(&bounds);
+    }
+
+    // The closest face of any mesh along a ray in world coordinates.
+    pub fn intersect_ray(&self, ray: &Ray, max_t: f64) -> Option<SceneHit> {
+        self.bvh.intersect_ray(ray, max_t, |i, max_t| {
+            let instance = &self.instances[i];
+            // The direction isn't normalised after this, so t is the same in both.
+            let local = ray.transformed(&instance.inverse?);
+            let hit = instance.mesh.geometry.intersect_ray(&local, max_t)?;
+
+            Some((hit.t, SceneHit { instance: i, hit }))
+        })
+    }
+
+    // Every instance whose bounding box overlaps aabb.
+    #[allow(dead_code)]
+    pub fn instances_in_box(&self, aabb: &Aabb) -> Vec<usize> {
+        let mut instances = Vec::new();
+
+        self.bvh.query_box(aabb, |i| {
+            if self.instances[i].bounds.intersects(aabb) {
+                instances.push(i);
+            }
+        });
+
+        instances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn donut() -> Arc<Geometry> {
+        Arc::new(Geometry::from_file(String::from("objects/donut.obj")).unwrap())
+    }
+
+    // Rays from all round the outside towards points spread through the middle, so plenty hit and
+    // plenty miss.
+    fn rays() -> Vec<Ray> {
+        let mut rays = Vec::new();
+
+        for i in 0..12 {
+            let a = i as f64 * 0.53;
+            let origin = Vector::point(4.0 * a.cos(), 3.0 * (a * 1.7).sin(), 4.0 * a.sin());
+
+            for j in 0..25 {
+                let target = Vector::point((j % 5) as f64 * 0.6 - 1.2, 0.1 * i as f64 - 0.5, (j / 5) as f64 * 0.6 - 1.2);
+
+                rays.push(Ray::new(origin, target - origin));
+            }
+        }
+
+        rays
+    }
+
+    fn triangle(g: &Geometry, face: usize) -> (Vector, Vector, Vector) {
+        let v = &g.faces[face].vertices;
+
+        (g.vertices[v[0]], g.vertices[v[1]], g.vertices[v[2]])
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let g = donut();
+        let bounds = g.face_bounds();
+
+        for method in [SplitMethod::Median, SplitMethod::Sah] {
+            let bvh = Bvh::build(&bounds, method);
+            let mut hits = 0;
+
+            for ray in rays() {
+                let found = bvh.intersect_ray(&ray, f64::INFINITY, |i, max_t| {
+                    let (a, b, c) = triangle(&g, i);
+                    let (t, _) = ray.intersect_triangle(&a, &b, &c)?;
+
+                    if t < max_t { Some((t, t)) } else { None }
+                });
+
+                let expected = (0..g.faces.len())
+                    .filter_map(|i| {
+                        let (a, b, c) = triangle(&g, i);
+
+                        ray.intersect_triangle(&a, &b, &c).map(|(t, _)| t)
+                    })
+                    .fold(None, |closest: Option<f64>, t| Some(closest.map_or(t, |c| c.min(t))));
+
+                // The same faces are tested the same way, so the distances should be exactly equal.
+                assert_eq!(found.map(f64::to_bits), expected.map(f64::to_bits), "{:?}", method);
+                hits += found.is_some() as usize;
+            }
+
+            assert!(hits > 0 && hits < rays().len(), "{:?}: {} hits", method, hits);
+
+            for i in 0..27 {
+                let centre = Vector::point((i % 3) as f64 - 1.0, (i / 3 % 3) as f64 * 0.3 - 0.3, (i / 9) as f64 - 1.0);
+                let query = Aabb::from_points(&[centre - Vector::direction(0.3, 0.2, 0.3), centre + Vector::direction(0.3, 0.2, 0.3)]);
+
+                let mut found = Vec::new();
+                bvh.query_box(&query, |i| {
+                    if bounds[i].intersects(&query) {
+                        found.push(i);
+                    }
+                });
+                found.sort_unstable();
+
+                let expected: Vec<usize> = (0..bounds.len()).filter(|i| bounds[*i].intersects(&query)).collect();
+
+                assert_eq!(found, expected, "{:?}", method);
+            }
+        }
+    }
+
+    // After moving an instance and refitting, rays find it where it's gone and not where it was.
+    #[test]
+    fn refit_follows_moved_instances() {
+        let g = donut();
+        let meshes: Vec<Mesh> = (0..4).map(|i| Mesh::from(Arc::clone(&g), Vector::point(i as f64 * 3.0, 0.0, 0.0), Quaternion::identity())).collect();
+        let pairs: Vec<(Matrix, &Mesh)> = meshes.iter().map(|m| (Matrix::identity(), m)).collect();
+        let mut bvh = SceneBvh::build(&pairs);
+
+        // Straight down through the ring of each donut.
+        let down = |x: f64| Ray::new(Vector::point(x + 1.0, 5.0, 0.0), Vector::direction(0.0, -1.0, 0.0));
+
+        assert_eq!(bvh.intersect_ray(&down(3.0), f64::INFINITY).map(|h| h.instance), Some(1));
+        assert!(bvh.intersect_ray(&down(30.0), f64::INFINITY).is_none());
+
+        bvh.set_world(1, Matrix::translation(Vector::direction(27.0, 0.0, 0.0)));
+        bvh.refit();
+
+        assert_eq!(bvh.intersect_ray(&down(30.0), f64::INFINITY).map(|h| h.instance), Some(1));
+        assert!(bvh.intersect_ray(&down(3.0), f64::INFINITY).is_none());
+
+        let around = |x: f64| Aabb::from_points(&[Vector::point(x - 0.5, -0.5, -0.5), Vector::point(x + 0.5, 0.5, 0.5)]);
+
+        assert_eq!(bvh.instances_in_box(&around(30.0)), vec![1]);
+        assert!(bvh.instances_in_box(&around(3.0)).is_empty());
+    }
+}
@@ -1,42 +1,300 @@
 // The device which renders the objects and draws them to the screen. They are all contained in
 // this one device struct which is why I'm giving it its own file.
 
+use crate::antialias::*;
+use crate::bounds::*;
+use crate::glyphs::*;
+use crate::lighting::*;
+use crate::overlay::*;
+use crate::pipeline::*;
+use crate::postprocess::*;
+use crate::raster::*;
 use crate::render_objects::*;
+use crate::scene::*;
+use crate::shader::*;
+use crate::shadow::*;
+use crate::simd::*;
+use crate::stats::*;
 use crate::structures::*;
-use std::cmp;
+use crate::target::*;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+// Terminal cells are roughly twice as tall as they are wide, so anything that should look the same
+// size both ways needs stretching sideways by this much.
+pub const CELL_ASPECT: f64 = 2.0;
+
+// The ways a frame can be drawn. See raytrace.rs for the second one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Renderer {
+    Rasteriser,
+    RayTracer,
+}
+
+// What the rasteriser draws. Can be changed between any two frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    // Just the edges of every face, front or back, with nothing hidden.
+    Wireframe,
+    // The edges of the faces facing the camera, with anything behind another face hidden.
+    HiddenLine,
+    // Faces filled in with their own colour, no lighting.
+    Solid,
+    // Faces filled in and lit. The normal one.
+    Lit,
+    // Faces coloured by which way they face (see NormalShader).
+    Normals,
+    // Faces coloured by how far away they are, white being closest (see DepthShader).
+    Depth,
+    // Lit in a few flat bands, with outlines drawn around everything (see toon.rs).
+    Toon,
+}
+
+// Which mesh something is: either one of Device::meshes or the mesh on a node of the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeshId {
+    Device(usize),
+    Node(NodeId),
+}
 
 pub struct Device {
     // The width and height of the screen. This will of course be the dimensions of the terminal.
     pub dimensions: (usize, usize),
     pub camera: Camera,
     pub meshes: Vec<Mesh>,
+    // Anything in the scene graph is drawn as well as meshes.
+    pub scene: Scene,
+    // Lights that aren't part of the scene graph, and how much light everything gets regardless.
+    pub lights: Vec<Light>,
+    pub ambient: f64,
+    // How surfaces are lit. See lighting.rs.
+    pub lighting: LightingModel,
+    // The framebuffer, one colour per character cell, stored row by row.
     pub pixels: Vec<Colour>,
+    // Whether each pixel has been drawn to this frame. Pixels that haven't are left blank.
+    pub drawn: Vec<bool>,
+    // Depth of whatever is in each pixel. Smaller is closer, and cleared to infinity.
+    pub depth: Vec<f64>,
+    // The ID buffer: which face is in each pixel, for picking. Each is an index into id_faces plus
+    // one, with 0 meaning nothing. Only filled in if id_buffer is set.
+    pub ids: Vec<u32>,
+    pub id_faces: Vec<(MeshId, usize)>,
+    // The material and world space normal of each face in id_faces, in the same order. Cells are
+    // lit from these after everything's drawn (see lighting.rs and toon.rs).
+    pub id_shading: Vec<(Material, Vector)>,
+    pub id_buffer: bool,
+    pub background: Colour,
+    // Characters drawn on top of everything else (text, boxes etc). See overlay.rs.
+    pub overlay: Vec<Option<char>>,
+    // Characters the renderer has picked for cells itself (e.g. outlines), rather than going by
+    // the brightness of the pixel. Underneath the overlay.
+    pub glyphs: Vec<Option<char>>,
+    // How the rest of the characters are picked (see glyphs.rs), and how sharp a change in
+    // brightness has to be, from 0 to 1, to count as an edge for GlyphMode::Shape.
+    pub glyph_mode: GlyphMode,
+    pub edge_threshold: f64,
+    // Statistics for the frame currently being drawn, and for the last finished frame.
+    pub stats: FrameStats,
+    pub last_stats: FrameStats,
+    // Draw the stats of the last frame over the top left of the screen.
+    pub show_stats: bool,
+    // How many threads to rasterise with. 1 draws everything straight into the framebuffer. Only
+    // draw_triangles is split up between them: antialiased triangles (which go into a sample
+    // buffer) and anything drawn with shaders are always drawn on the calling thread.
+    pub threads: usize,
+    pub renderer: Renderer,
+    pub mode: RenderMode,
+    // How the edges are drawn in RenderMode::Wireframe, e.g. dashed.
+    pub wireframe_style: LineStyle,
+    // For the ray tracer: how many times a ray can bounce off reflective surfaces, and how many
+    // rays across and down each cell is split into.
+    pub ray_depth: usize,
+    pub ray_samples: usize,
+    // Shadow mapping for directional lights and spotlights (see shadow.rs). The bias (in world
+    // units) stops surfaces shadowing themselves, and pcf is how many texels either side are
+    // averaged.
+    pub shadows: bool,
+    pub shadow_map_size: usize,
+    pub shadow_bias: f64,
+    pub shadow_pcf: usize,
+    // For the toon mode: how many bands of brightness there are, how sharp a crease (in radians)
+    // has to be to get outlined, and how much further away (as a fraction of the distance) what's
+    // behind something has to be for its silhouette to be outlined.
+    pub toon_bands: usize,
+    pub crease_angle: f64,
+    pub outline_depth: f64,
+    // Antialiasing for filled triangles (see antialias.rs), and the samples of the last frame
+    // drawn with it.
+    pub antialiasing: Antialiasing,
+    pub sample_buffer: Option<Tile>,
+    // Run over the framebuffer in order at the end of render (see postprocess.rs).
+    pub post_processing: Vec<PostProcess>,
 }
 
 impl Device {
+    // Fills the whole terminal.
     pub fn new(camera: Camera, meshes: Vec<Mesh>, colour_space: Colour) -> Device {
+        Device::with_dimensions(camera, meshes, colour_space, term_size::dimensions().unwrap())
+    }
+
+    // A framebuffer of any size, (width, height) in cells, e.g. for drawing with no terminal.
+    pub fn with_dimensions(camera: Camera, meshes: Vec<Mesh>, colour_space: Colour, dimensions: (usize, usize)) -> Device {
         let colour = match colour_space {
-            Colour::Rgba(r,g,b,a) => Colour::Rgba(0.0, 0.0, 0.0, 0.0),
-            Colour::Grey(a) => Colour::Grey(0.0),
+            Colour::Rgba(..) => Colour::Rgba(0.0, 0.0, 0.0, 0.0),
+            Colour::Grey(_) => Colour::Grey(0.0),
         };
 
-        let dimensions = term_size::dimensions().unwrap();
-
         Device {
-            dimensions: term_size::dimensions().unwrap(),
-            camera: camera,
-            meshes: meshes,
-            pixels: vec![colour; dimensions.0 * dimensions.1],
+            dimensions,
+            camera,
+            meshes,
+            scene: Scene::new(),
+            lights: Vec::new(),
+            ambient: 0.1,
+            lighting: LightingModel::Lambert,
+            pixels: vec![colour.clone(); dimensions.0 * dimensions.1],
+            drawn: vec![false; dimensions.0 * dimensions.1],
+            depth: vec![f64::INFINITY; dimensions.0 * dimensions.1],
+            ids: vec![0; dimensions.0 * dimensions.1],
+            id_faces: Vec::new(),
+            id_shading: Vec::new(),
+            id_buffer: false,
+            background: colour,
+            overlay: vec![None; dimensions.0 * dimensions.1],
+            glyphs: vec![None; dimensions.0 * dimensions.1],
+            glyph_mode: GlyphMode::Intensity,
+            edge_threshold: 0.3,
+            stats: FrameStats::new(),
+            last_stats: FrameStats::new(),
+            show_stats: false,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            renderer: Renderer::Rasteriser,
+            mode: RenderMode::Lit,
+            wireframe_style: LineStyle::Solid,
+            ray_depth: 2,
+            ray_samples: 1,
+            shadows: false,
+            shadow_map_size: 256,
+            shadow_bias: 0.1,
+            shadow_pcf: 1,
+            toon_bands: 4,
+            crease_angle: 0.8,
+            outline_depth: 0.1,
+            antialiasing: Antialiasing::Off,
+            sample_buffer: None,
+            post_processing: Vec::new(),
         }
     }
 
-    // Make an array of pixels, probably one dimensional array. Then make a function that takes in
-    // an x and y and sets that pixel to that colour. Then make functions for lines and triangles.
-    
+    // The screen's width / height as it actually looks, rather than in cells, for the projection.
+    // Everything that projects onto the screen or back off it should use this so they line up.
+    pub fn aspect(&self) -> f64 {
+        self.dimensions.0 as f64 / (self.dimensions.1 as f64 * CELL_ASPECT)
+    }
+
     pub fn clear_screen(&self) {
         print!("{}[2J", 27 as char);
     }
+
+    // Wipe the framebuffer and start a new frame.
+    pub fn clear(&mut self) {
+        for p in self.pixels.iter_mut() {
+            *p = self.background.clone();
+        }
+
+        for d in self.drawn.iter_mut() {
+            *d = false;
+        }
+
+        for d in self.depth.iter_mut() {
+            *d = f64::INFINITY;
+        }
+
+        for o in self.overlay.iter_mut() {
+            *o = None;
+        }
+
+        for g in self.glyphs.iter_mut() {
+            *g = None;
+        }
+
+        for i in self.ids.iter_mut() {
+            *i = 0;
+        }
+
+        self.id_faces.clear();
+        self.id_shading.clear();
+        self.sample_buffer = None;
+
+        self.last_stats = self.stats.clone();
+        self.stats.reset();
+    }
+
+    // Print the framebuffer to the terminal in one go.
+    pub fn present(&mut self) {
+        let start = Instant::now();
+        let (width, height) = self.dimensions;
+        let mut rows: Vec<Vec<char>> = Vec::with_capacity(height);
+
+        if self.show_stats {
+            let hud = self.last_stats.hud_lines().join("\n");
+            self.draw_label(0, 0, &hud, BoxStyle::Light);
+        }
+
+        if self.glyph_mode == GlyphMode::Shape {
+            self.shape_glyphs();
+        }
+
+        for y in 0..height {
+            let row = (0..width)
+                .map(|x| {
+                    let i = y * width + x;
+
+                    if let Some(c) = self.overlay[i] {
+                        c
+                    }
+
+                    else if let Some(c) = self.glyphs[i] {
+                        c
+                    }
+
+                    else if self.drawn[i] {
+                        Device::colour_to_char(self.pixels[i].clone())
+                    }
+
+                    else {
+                        ' '
+                    }
+                })
+                .collect();
+
+            rows.push(row);
+        }
+
+        // Move to the top left and write every row out, rather than jumping the cursor around for
+        // every single pixel.
+        let mut frame = format!("{}[H", 27 as char);
+
+        for (y, row) in rows.iter().enumerate() {
+            frame.extend(row.iter());
+
+            if y + 1 < height {
+                frame.push('\n');
+            }
+        }
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        handle.write_all(frame.as_bytes()).unwrap();
+        handle.flush().unwrap();
+
+        self.stats.present_time += start.elapsed();
+    }
     
+    #[allow(dead_code)]
     pub fn show_cursor(&self, show: bool) {
         if show {
             print!("{}[?25h", 27 as char);
@@ -47,321 +305,451 @@ impl Device {
         }
     }
 
-    fn move_cursor_to(&self, x: usize, y: usize) {
-        print!("{}[{};{}H", 27 as char, (x+1).to_string(), (y+1).to_string());
-    }
+    fn colour_to_char(colour: Colour) -> char {
+        let alpha = colour.intensity();
 
-    pub fn draw_point(&self, x: usize, y: usize, colour: Colour) {
-        self.move_cursor_to(x, y);
-        
-        print!("{}", Device::colour_to_char(colour));
-    }
+        // █#&+-
+        if alpha <= 0.2 {
+            '-'
+        }
 
-    pub fn draw_line_fast(&self, p1: (usize, usize), p2: (usize, usize), colour: Colour) {
-        // Implementing Bresenham's Line Algorithm:
-        // This is fast but provides no antialiasing.
-        
-        let pp1 = (p1.1, p1.0);
-        let pp2 = (p2.1, p2.0);
-        
-        if (pp2.1 as i32 - pp1.1 as i32).abs() < (pp2.0 as i32 - pp1.0 as i32).abs() {
-            if pp1.0 > pp2.0 {
-                self.draw_line_low(pp2, pp1, colour.clone());
-            }
+        else if alpha <= 0.4 {
+            '+'
+        }
 
-            else {
-                self.draw_line_low(pp1, pp2, colour.clone());
-            }
+        else if alpha <= 0.6 {
+            '&'
+        }
+
+        else if alpha <= 0.8 {
+            '#'
         }
 
         else {
-            if pp1.1 > pp2.1 {
-                self.draw_line_high(pp2, pp1, colour.clone());
-            }
+            '█'
+        }
+    }
 
-            else {
-                self.draw_line_high(pp1, pp2, colour.clone());
-            }
+    pub fn render(&mut self) {
+        match (self.renderer, self.mode) {
+            (Renderer::RayTracer, _) => self.ray_trace(),
+            (Renderer::Rasteriser, RenderMode::Normals) => self.render_with(&StandardVertex, &NormalShader, &()),
+            (Renderer::Rasteriser, RenderMode::Depth) => {
+                let depth = self.depth_range();
+                self.render_with(&StandardVertex, &depth, &());
+            },
+            (Renderer::Rasteriser, _) => self.rasterise(),
         }
+
+        let start = Instant::now();
+
+        self.post_process();
+        self.stats.post_time += start.elapsed();
     }
 
-    fn draw_line_low(&self, p1: (usize, usize), p2: (usize, usize), colour: Colour) {
-        let mut dx = p2.0 as f64 - p1.0 as f64;
-        let mut dy = p2.1 as f64 - p1.1 as f64;
+    fn rasterise(&mut self) {
+        // Wow, the main render function! Snazzy.
+        // First, generate the MVP matricies: Model, View, Projection.
+        // Model matrix: the matrix that describes the basic position, rotation and scaling of each
+        // mesh. The mesh itself is never changed, its vertices are copied and transformed here.
+        let start = Instant::now();
+
+        self.scene.update();
 
-        let mut yi = 1;
+        let view = self.camera.view_matrix();
+        let projection = self.camera.projection_matrix(self.aspect());
+        let view_projection = projection * view;
+        let frustum = Frustum::from_matrix(&view_projection);
+
+        let mut lights = self.lights.clone();
+        lights.extend(self.scene.lights());
+
+        let meshes = instances(&self.meshes, &self.scene);
+
+        // The per-face colour is only ever diffuse. Anything more is added per cell.
+        let illumination = Illumination {
+            model: LightingModel::Lambert,
+            lights: &lights,
+            ambient: self.ambient,
+            eye: self.camera.pos,
+        };
 
-        if dy < 0.0 {
-            yi = -1;
-            dy *= -1.0;
+        // Shadows and specular highlights are done afterwards for each cell, so they need to know
+        // what's in each cell and how it's lit: the material and normal of each face, kept in
+        // id_shading.
+        let lit = matches!(self.mode, RenderMode::Lit | RenderMode::Toon);
+        let per_cell = lit && (self.shadows || self.lighting != LightingModel::Lambert);
+        let mut shadow_maps: Vec<Option<ShadowMap>> = Vec::new();
+
+        if per_cell && self.shadows {
+            let bounds = meshes.iter().fold(Aabb::empty(), |b, (_, world, m)| b.union(&m.bounds(&(world * m.model_matrix())).0));
+
+            shadow_maps = lights.iter().map(|l| ShadowMap::render(l, &bounds, &meshes, self.shadow_map_size)).collect();
         }
 
-        let mut D = 2.0*dy - dx;
-        let mut y = p1.1 as i32;
+        let mut triangles: Vec<ScreenTriangle> = Vec::new();
+        let mut clip_space: Vec<Vector> = Vec::new();
+
+        for &(mesh_id, world, m) in &meshes {
+            let model = world * m.model_matrix();
 
-        for x in p1.0..p2.0 {
-            self.draw_point(x, y as usize, colour.clone());
+            // Throw away whole meshes that are out of view before doing anything with their
+            // triangles. The sphere is the cheaper test so it goes first.
+            let (aabb, sphere) = m.bounds(&model);
 
-            if D > 0.0 {
-                y += yi;
-                D -= 2.0*dx;
+            if !frustum.intersects_sphere(&sphere) || !frustum.intersects_aabb(&aabb) {
+                self.stats.meshes_culled += 1;
+                continue;
             }
 
-            D += 2.0*dy;
-        }
-    }
+            let mvp = view_projection * model;
+            let normals = normal_matrix(&model);
 
-    fn draw_line_high(&self, p1: (usize, usize), p2: (usize, usize), colour: Colour) {
-        let mut dx = p2.0 as f64 - p1.0 as f64;
-        let mut dy = p2.1 as f64 - p1.1 as f64;
+            clip_space.clear();
+            clip_space.extend_from_slice(&m.geometry.vertices);
+            transform_vectors(&mvp, &mut clip_space);
 
-        let mut xi: i32 = 1;
+            for (face, f) in m.geometry.faces.iter().enumerate() {
+                self.stats.triangles_submitted += 1;
 
-        if dx < 0.0 {
-            xi = -1;
-            dx *= -1.0;
-        }
+                let clip = [clip_space[f.vertices[0]], clip_space[f.vertices[1]], clip_space[f.vertices[2]]];
+                let codes = [outcode(&clip[0]), outcode(&clip[1]), outcode(&clip[2])];
 
-        let mut D = 2.0*dx - dy;
-        let mut x = p1.1 as i32;
+                // Entirely outside one of the planes of the view
+                if codes[0] & codes[1] & codes[2] != 0 {
+                    self.stats.triangles_culled += 1;
+                    continue;
+                }
 
-        for y in p1.1..p2.1 {
-            self.draw_point(x as usize, y, colour.clone());
+                if codes[0] | codes[1] | codes[2] != 0 {
+                    self.stats.triangles_clipped += 1;
+                }
+
+                // Flat shading: one colour for the whole face, worked out in world space.
+                let v = &m.geometry.vertices;
+                let (a, b, c) = (v[f.vertices[0]], v[f.vertices[1]], v[f.vertices[2]]);
+                let centre = model * Vector::point((a.x + b.x + c.x) / 3.0, (a.y + b.y + c.y) / 3.0, (a.z + b.z + c.z) / 3.0);
+                let normal = transform_normal(&normals, f.normal);
+                let material = m.face_material(f);
+                let colour = if lit {
+                    shade(&illumination, &material, &centre, &normal, |_| 1.0)
+                }
 
-            if D > 0.0 {
-                x += xi;
-                D -= 2.0*dy;
+                else {
+                    material.colour.clone()
+                };
+                let mut id = 0;
+
+                for piece in clip_near(clip) {
+                    let screen = [
+                        to_screen(&piece[0], self.dimensions),
+                        to_screen(&piece[1], self.dimensions),
+                        to_screen(&piece[2], self.dimensions),
+                    ];
+
+                    let points = [(screen[0].0, screen[0].1), (screen[1].0, screen[1].1), (screen[2].0, screen[2].1)];
+
+                    // Faces are wound anticlockwise when looking at the front of them. The screen's
+                    // y goes down, which flips that, so anything anticlockwise on screen is facing
+                    // away from us.
+                    let area = (points[1].0 - points[0].0) * (points[2].1 - points[0].1)
+                        - (points[1].1 - points[0].1) * (points[2].0 - points[0].0);
+
+                    // Wireframes show the back of things too.
+                    if area >= 0.0 && self.mode != RenderMode::Wireframe {
+                        self.stats.triangles_culled += 1;
+                        continue;
+                    }
+
+                    if (self.id_buffer || per_cell || self.mode == RenderMode::Toon) && id == 0 {
+                        self.id_faces.push((mesh_id, face));
+                        self.id_shading.push((material.clone(), normal));
+                        id = self.id_faces.len() as u32;
+                    }
+
+                    triangles.push(ScreenTriangle {
+                        points,
+                        depths: [screen[0].2, screen[1].2, screen[2].2],
+                        colour: colour.clone(),
+                        id,
+                    });
+                }
             }
+        }
+
+        self.stats.transform_time += start.elapsed();
 
-            D += 2.0*dx;
+        let start = Instant::now();
+
+        match self.mode {
+            RenderMode::Wireframe => {
+                for t in &triangles {
+                    self.draw_wireframe_triangle(t.points[0], t.points[1], t.points[2], t.colour.clone(), self.wireframe_style);
+                }
+            },
+
+            RenderMode::HiddenLine => self.draw_hidden_line(&triangles),
+            _ => self.draw_filled(&triangles),
         }
-    }
 
-    pub fn draw_line_antialiased(&self, p1: (f64, f64), p2: (f64, f64), colour: Colour) {
-        // Implementing Wu's Line Algorithm:
-        // This is slow but antialiased
-        // The reason this takes f64 input and not usize like draw_line_fast is because this can
-        // give you a line that is not necessarily drawn *from* one pixel to another. Instead since
-        // it is antialiased it can give you something that better approximates a line from a float
-        // value instead of just integers.
-        
-        // Funny story, I tried this normally once and for some reason it would swap the x and y
-        // values for each point around. There's a problem in my code somewhere but my reasoning is
-        // that if I simply swap them around beforehand it will all work out in the end.
-        // Modern problems require modern solutions.
-        let mut y0 = p1.0; let mut y1 = p2.0;
-        let mut x0 = p1.1; let mut x1 = p2.1;
-        
-        let steep = (y1 - y0).abs() > (x1 - x0).abs();
-
-        if steep {
-            // Swap x0 and y0
-            let mut temp = x0;
-            x0 = y0;
-            y0 = temp;
-
-            // Swap x1 and y1
-            temp = x1;
-            x1 = y1;
-            y1 = temp;
-        }
-
-        if x0 > x1 {
-            // Swap x0 and x1
-            let mut temp = x0;
-            x0 = x1;
-            x1 = temp;
-
-            // Swap y0 and y1
-            temp = y0;
-            y0 = y1;
-            y1 = temp;
-        }
-
-        let dx = x1 - x0;
-        let dy = y1 - y0;
-        let mut gradient = 1f64;
-
-        if dx != 0.0 {
-            gradient = dy/dx;
-        }
-
-        // First endpoint. If you haven't realised by now I'm pretty much just reading this off
-        // Wikipedia. Donate $3!
-        let mut xend = x0.round();
-        let mut yend = y0 + gradient * (xend-x0);
-
-        let xgap = 1.0-((x0+0.5).fract());
-        let mut xpxl1 = xend;
-        let mut ypxl1 = yend.trunc();
-
-        if steep {
-            self.draw_point(ypxl1 as usize, xpxl1 as usize, Colour::Grey((1.0-(yend.fract())) * xgap));
-            self.draw_point((ypxl1+1.0) as usize, xpxl1 as usize, Colour::Grey((yend.fract()) * xgap));
+        if per_cell {
+            self.shade_cells(&lights, &shadow_maps, &view_projection);
         }
 
-        else {
-            self.draw_point(xpxl1 as usize, ypxl1 as usize, Colour::Grey((1.0-(yend.fract())) * xgap));
-            self.draw_point(xpxl1 as usize, (ypxl1+1.0) as usize, Colour::Grey((yend.fract()) * xgap));
+        if self.mode == RenderMode::Toon {
+            self.toon();
         }
 
-        let mut intery = yend + gradient;
+        self.stats.raster_time += start.elapsed();
+    }
 
-        // Second endpoint. Same thing.
-        let mut xend = x1.round();
-        let mut yend = y1 + gradient * (xend-x1);
+    // Fills the faces in with the background first so they hide whatever's behind them, then draws
+    // their edges over the top. The faces are pushed back a little (as far as their depth changes
+    // over one cell) so their own edges aren't hidden by them.
+    fn draw_hidden_line(&mut self, triangles: &[ScreenTriangle]) {
+        let filled: Vec<ScreenTriangle> = triangles.iter()
+            .map(|t| {
+                let [(x0, y0), (x1, y1), (x2, y2)] = t.points;
+                let [z0, z1, z2] = t.depths;
+                let area = (x1 - x0) * (y2 - y0) - (x2 - x0) * (y1 - y0);
+
+                // How much the depth changes going one cell across and one cell down.
+                let dzdx = ((z1 - z0) * (y2 - y0) - (z2 - z0) * (y1 - y0)) / area;
+                let dzdy = ((z2 - z0) * (x1 - x0) - (z1 - z0) * (x2 - x0)) / area;
+                let offset = dzdx.abs().max(dzdy.abs()) + 1e-7;
+
+                ScreenTriangle {
+                    depths: [z0 + offset, z1 + offset, z2 + offset],
+                    colour: self.background.clone(),
+                    ..t.clone()
+                }
+            })
+            .collect();
+
+        // Only the depth of the faces is wanted, so whatever they cover is left blank.
+        let drawn = self.drawn.clone();
+
+        self.draw_triangles(&filled, false);
+        self.drawn = drawn;
 
-        let xgap = 1.0-((x1+0.5).fract());
-        let mut xpxl2 = xend;
-        let mut ypxl2 = yend.trunc();
+        for t in triangles {
+            for i in 0..3 {
+                let (a, b) = (i, (i + 1) % 3);
 
-        if steep {
-            self.draw_point(ypxl2 as usize, xpxl2 as usize, Colour::Grey((1.0-(yend.fract())) * xgap));
-            self.draw_point((ypxl2+1.0) as usize, xpxl2 as usize, Colour::Grey((yend.fract()) * xgap));
+                self.draw_line_antialiased_depth(
+                    (t.points[a].0, t.points[a].1, t.depths[a]),
+                    (t.points[b].0, t.points[b].1, t.depths[b]),
+                    t.colour.clone(),
+                );
+            }
         }
+    }
 
-        else {
-            self.draw_point(xpxl2 as usize, ypxl2 as usize, Colour::Grey((1.0-(yend.fract())) * xgap));
-            self.draw_point(xpxl2 as usize, (ypxl2+1.0) as usize, Colour::Grey((yend.fract()) * xgap));
+    // For the depth mode: the distances from the camera to the nearest and furthest points the
+    // scene could have, so the whole range of brightness is used.
+    fn depth_range(&mut self) -> DepthShader {
+        self.scene.update();
+
+        let bounds = instances(&self.meshes, &self.scene)
+            .iter()
+            .fold(Aabb::empty(), |b, (_, world, m)| b.union(&m.bounds(&(world * m.model_matrix())).0));
+
+        if bounds.is_empty() {
+            return DepthShader { near: self.camera.near, far: self.camera.far };
         }
 
-        if steep {
-            for x in (xpxl1 as usize + 1)..(xpxl2 as usize) {
-                self.draw_point(intery.trunc() as usize, x, Colour::Grey(1.0-intery.fract()));
-                self.draw_point(intery.trunc() as usize + 1, x, Colour::Grey(intery.fract()));
-                intery += gradient;
+        let distance = Vector::distance(&self.camera.pos, &bounds.centre());
+        let radius = bounds.size().magnitude() * 0.5;
+
+        DepthShader {
+            near: (distance - radius).max(self.camera.near),
+            far: (distance + radius).min(self.camera.far),
+        }
+    }
+
+    // Draw a whole batch of triangles in order. With more than one thread the screen is split into
+    // bins which are drawn in parallel, which comes out exactly the same as drawing them one by one.
+    pub fn draw_triangles(&mut self, triangles: &[ScreenTriangle], antialiased: bool) {
+        if self.threads <= 1 {
+            for t in triangles {
+                self.draw_screen_triangle(t, antialiased);
             }
+
+            return;
         }
 
-        else {
-            for x in (xpxl1 as usize + 1)..(xpxl2 as usize) {
-                self.draw_point(x, intery.trunc() as usize, Colour::Grey(1.0-intery.fract()));
-                self.draw_point(x, intery.trunc() as usize + 1, Colour::Grey(intery.fract()));
-                intery += gradient;
+        let bins = bin_triangles(triangles, self.dimensions);
+        let tiles: Vec<Mutex<Tile>> = bins.iter().map(|(rect, _)| Mutex::new(self.tile(*rect))).collect();
+        let next = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..self.threads.min(tiles.len()) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+
+                    if i >= tiles.len() {
+                        break;
+                    }
+
+                    let mut tile = tiles[i].lock().unwrap();
+
+                    for &j in &bins[i].1 {
+                        tile.draw_screen_triangle(&triangles[j], antialiased);
+                    }
+                });
             }
+        });
+
+        for tile in tiles {
+            self.put_tile(tile.into_inner().unwrap());
         }
     }
 
-    fn colour_to_char(colour: Colour) -> char {
-        let alpha = match colour {
-            Colour::Rgba(r,g,b,a) => a,
-            Colour::Grey(a) => a,
+    // Copy part of the framebuffer out into a tile.
+    pub fn tile(&self, rect: Rect) -> Tile {
+        let width = rect.2 - rect.0;
+        let size = width * (rect.3 - rect.1);
+        let mut tile = Tile {
+            viewport: self.dimensions,
+            rect,
+            pixels: Vec::with_capacity(size),
+            drawn: Vec::with_capacity(size),
+            depth: Vec::with_capacity(size),
+            ids: Vec::with_capacity(size),
+            stats: FrameStats::new(),
         };
 
-        // █#&+-
-        if alpha <= 0.2 {
-            return '-';
-        }
+        for y in rect.1..rect.3 {
+            let row = y * self.dimensions.0;
 
-        else if alpha <= 0.4 {
-            return '+';
+            tile.pixels.extend_from_slice(&self.pixels[(row + rect.0)..(row + rect.2)]);
+            tile.drawn.extend_from_slice(&self.drawn[(row + rect.0)..(row + rect.2)]);
+            tile.depth.extend_from_slice(&self.depth[(row + rect.0)..(row + rect.2)]);
+            tile.ids.extend_from_slice(&self.ids[(row + rect.0)..(row + rect.2)]);
         }
 
-        else if alpha <= 0.6 {
-            return '&';
-        }
+        tile
+    }
 
-        else if alpha <= 0.8 {
-            return '#';
-        }
+    // Copy a tile back into the framebuffer, along with what it added to the stats.
+    pub fn put_tile(&mut self, tile: Tile) {
+        let rect = tile.rect;
+        let width = rect.2 - rect.0;
 
-        else {
-            return '█';
-        }
-    }
+        for y in rect.1..rect.3 {
+            let row = y * self.dimensions.0;
+            let src = (y - rect.1) * width;
 
-    pub fn draw_triangle(&self, p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), colour: Colour, antialiased: bool) {
-        // First, draw the lines between each vertex.
-        if antialiased {
-            self.draw_line_antialiased(p1, p2, colour.clone());
-            self.draw_line_antialiased(p2, p3, colour.clone());
-            self.draw_line_antialiased(p3, p1, colour.clone());
-            self.fill_triangle((p1.1, p1.0), (p2.1, p2.0), (p3.1, p3.0), colour.clone());
+            self.pixels[(row + rect.0)..(row + rect.2)].clone_from_slice(&tile.pixels[src..(src + width)]);
+            self.drawn[(row + rect.0)..(row + rect.2)].copy_from_slice(&tile.drawn[src..(src + width)]);
+            self.depth[(row + rect.0)..(row + rect.2)].copy_from_slice(&tile.depth[src..(src + width)]);
+            self.ids[(row + rect.0)..(row + rect.2)].copy_from_slice(&tile.ids[src..(src + width)]);
         }
 
-        else {
-            // This doesn't quite work yet... Just don't use it?
-            self.draw_line_fast((p1.0 as usize, p1.1 as usize), (p2.0 as usize, p2.1 as usize), colour.clone());
-            self.draw_line_fast((p2.0 as usize, p2.1 as usize), (p3.0 as usize, p3.1 as usize), colour.clone());
-            self.draw_line_fast((p3.0 as usize, p3.1 as usize), (p1.0 as usize, p1.1 as usize), colour.clone());
-            self.fill_triangle((p1.1, p1.0), (p2.1, p2.0), (p3.1, p3.0), colour.clone());
-        }
+        self.stats.pixels_shaded += tile.stats.pixels_shaded;
+        self.stats.overdraw += tile.stats.overdraw;
     }
+}
 
-    pub fn fill_triangle(&self, p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), colour: Colour) {
-        let min = (f64::min(f64::min(p1.0, p2.0), p3.0), f64::min(f64::min(p1.1, p2.1), p3.1));
-        let max = (f64::max(f64::max(p1.0, p2.0), p3.0), f64::max(f64::max(p1.1, p2.1), p3.1));
+// Every mesh to be drawn along with its world matrix, which doesn't include the mesh's own model
+// matrix. The scene needs to have been updated first.
+pub fn instances<'a>(meshes: &'a [Mesh], scene: &'a Scene) -> Vec<(MeshId, Matrix, &'a Mesh)> {
+    meshes.iter()
+        .enumerate()
+        .map(|(i, m)| (MeshId::Device(i), Matrix::identity(), m))
+        .chain(scene.mesh_nodes().into_iter().map(|(id, world, m)| (MeshId::Node(id), world, m)))
+        .collect()
+}
 
-        let vs1 = (p2.0 - p1.0, p2.1 - p1.1);
-        let vs2 = (p3.0 - p1.0, p3.1 - p1.1);
+impl Target for Device {
+    fn viewport(&self) -> (usize, usize) {
+        self.dimensions
+    }
 
-        for x in (min.0 as usize)..(max.0 as usize+1) {
-            for y in (min.1 as usize)..(max.1 as usize+1) {
-                let q = (x as f64 - p1.0, y as f64 - p1.1);
+    fn bounds(&self) -> Rect {
+        (0, 0, self.dimensions.0, self.dimensions.1)
+    }
 
-                let s = Device::cross_point(q, vs2) / Device::cross_point(vs1, vs2);
-                let t = Device::cross_point(vs1, q) / Device::cross_point(vs1, vs2);
+    fn index(&self, x: usize, y: usize) -> usize {
+        y * self.dimensions.0 + x
+    }
 
-                if (s >= 0.0 && t >= 0.0) && (s+t <= 1.0) {
-                    self.draw_point(x as usize, y as usize, colour.clone());
-                }
-            }
+    fn buffers(&mut self) -> Buffers<'_> {
+        Buffers {
+            pixels: &mut self.pixels,
+            drawn: &mut self.drawn,
+            depth: &mut self.depth,
+            ids: &mut self.ids,
+            stats: &mut self.stats,
         }
     }
+}
 
-    fn cross_point(p1: (f64, f64), p2: (f64, f64)) -> f64 {
-        return (p1.0*p2.1)-(p1.1*p2.0);
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
 
-    pub fn render(&self) {
-        // Wow, the main render function! Snazzy.
-        // First, generate the MVP matricies: Model, View, Projection.
-        // Model matrix: the matrix that describes the basic position, rotation and scaling of each
-        // mesh.
-        let mvp_matrices: Vec<Matrix> = Vec::new();
-        
-        for m in &self.meshes {
-            let position = Matrix::translation(m.pos.clone());
-            
-        }
-         
-    }
+    fn scene(threads: usize, mode: RenderMode) -> Device {
+        let camera = Camera::from(Vector::from(3.0, 4.0, 5.0, 1.0), Vector::new(), Vector::from(0.0, 1.0, 0.0, 0.0));
+        let mut device = Device::with_dimensions(camera, Vec::new(), Colour::Grey(0.0), (120, 50));
+        let donut = Arc::new(Geometry::from_file(String::from("objects/donut.obj")).unwrap());
 
-    pub fn test_render(&self) {
-        // Gives an orthographic projection from the top. I use this only as a sanity check - it
-        // doesn't do any real perspective or anything that requires complex linear transformations
-        for m in &self.meshes {
-            for f in &m.faces {
-                let v = (
-                    m.vertices[f.vertices[0]].clone(),
-                    m.vertices[f.vertices[1]].clone(),
-                    m.vertices[f.vertices[2]].clone()
-                );
+        // A few overlapping donuts, so plenty of triangles cross the edges of the bins and get
+        // drawn over each other.
+        for i in 0..3 {
+            let pos = Vector::point(i as f64 * 0.7 - 0.7, 0.0, i as f64 * -0.5);
+            let rot = Quaternion::from_euler(0.3 * i as f64, 0.5, 0.0);
 
-                let scale_factor = 1.0;
+            device.meshes.push(Mesh::from(Arc::clone(&donut), pos, rot));
+        }
 
-                let scale  = (scale_factor * (self.dimensions.0 as f64 / 7.5), -scale_factor * (self.dimensions.1 as f64 / 4.0));
-                let offset = (self.dimensions.0 as f64 / 3.0, self.dimensions.1 as f64 / 3.0);
+        device.lights.push(Light::directional(Vector::direction(-1.0, -2.0, -1.0), Colour::Grey(1.0)));
+        device.id_buffer = true;
+        device.threads = threads;
+        device.mode = mode;
+        device
+    }
 
-                let points = [
-                    (
-                        ((v.0.x * scale.0) + offset.0),
-                        ((v.0.z * scale.1) + offset.1),
-                    ),
+    #[test]
+    fn threads_draw_the_same_as_one() {
+        for mode in [RenderMode::Lit, RenderMode::Solid, RenderMode::HiddenLine] {
+            let mut single = scene(1, mode);
+            let mut tiled = scene(8, mode);
+
+            single.render();
+            tiled.render();
+
+            // Colour has no PartialEq, and NaNs wouldn't compare equal anyway, so compare bits.
+            let bits = |d: &Device| -> Vec<[u64; 4]> {
+                d.pixels.iter().map(|c| {
+                    let (r, g, b, a) = c.to_rgba();
+                    [r.to_bits(), g.to_bits(), b.to_bits(), a.to_bits()]
+                }).collect()
+            };
+
+            assert!(single.drawn.iter().any(|d| *d), "{:?} drew nothing", mode);
+            assert!(bits(&single) == bits(&tiled), "{:?} pixels differ", mode);
+            assert!(single.depth.iter().map(|z| z.to_bits()).eq(tiled.depth.iter().map(|z| z.to_bits())), "{:?} depth differs", mode);
+            assert_eq!(single.drawn, tiled.drawn, "{:?}", mode);
+            assert_eq!(single.ids, tiled.ids, "{:?}", mode);
+        }
+    }
 
-                    (
-                        ((v.1.x * scale.0) + offset.0),
-                        ((v.1.z * scale.1) + offset.1),
-                    ),
+    // Without clearing in between, ids carry on from where the last frame's left off, and the
+    // cells still showing the last frame keep theirs.
+    #[test]
+    fn renders_twice_without_clearing() {
+        for mode in [RenderMode::Lit, RenderMode::Toon] {
+            let mut device = scene(1, mode);
 
-                    (
-                        ((v.2.x * scale.0) + offset.0),
-                        ((v.2.z * scale.1) + offset.1),
-                    )
-                ];
+            device.lighting = LightingModel::BlinnPhong;
+            device.render();
+            device.meshes[0].pos = Vector::point(-1.5, 0.5, 0.0);
+            device.render();
 
-                self.draw_triangle(points[0], points[1], points[2], Colour::Grey(0.8), true);
-            }
+            assert_eq!(device.id_faces.len(), device.id_shading.len(), "{:?}", mode);
+            assert!(device.ids.iter().all(|id| (*id as usize) <= device.id_faces.len()), "{:?}", mode);
         }
     }
 }
@@ -0,0 +1,74 @@
+// Depth fog. Things fade towards a colour (the background, unless told otherwise) the further
+// they are from the camera, which makes it much easier to tell what's in front of what once
+// everything's been turned into characters. Worked out from the depth buffer after everything's
+// drawn, so it's the same for every render mode and both renderers.
+
+use crate::device::*;
+use crate::render_objects::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum FogMode {
+    // Nothing at the start of the range up to completely fogged at the end.
+    Linear,
+    // 1 - e^(-density * distance). Never quite gets there, and builds up fastest close by.
+    Exponential(f64),
+    // 1 - e^(-(density * distance)^2). Stays clear for longer and then thickens up quickly.
+    ExponentialSquared(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Fog {
+    pub mode: FogMode,
+    // (start, end) in world units from the camera, for linear fog. None means the camera's near
+    // and far planes.
+    pub range: Option<(f64, f64)>,
+    // What things fade towards. None means the device's background.
+    pub colour: Option<Colour>,
+}
+
+impl Fog {
+    pub fn new(mode: FogMode) -> Fog {
+        Fog {
+            mode,
+            range: None,
+            colour: None,
+        }
+    }
+
+    // How fogged something distance away is, from 0 (not at all) to 1 (completely).
+    pub fn amount(&self, distance: f64, camera: &Camera) -> f64 {
+        let amount = match self.mode {
+            FogMode::Linear => {
+                let (start, end) = self.range.unwrap_or((camera.near, camera.far));
+
+                (distance - start) / (end - start).max(f64::EPSILON)
+            },
+            FogMode::Exponential(density) => 1.0 - (-density * distance).exp(),
+            FogMode::ExponentialSquared(density) => 1.0 - (-(density * distance).powi(2)).exp(),
+        };
+
+        amount.clamp(0.0, 1.0)
+    }
+}
+
+impl Device {
+    // Fades every drawn cell towards the fog's colour by how far away it is.
+    pub fn fog(&mut self, fog: &Fog) {
+        // The background is transparent when it's Rgba, which wouldn't cover anything up.
+        let colour = match fog.colour.as_ref().unwrap_or(&self.background) {
+            Colour::Rgba(r, g, b, _) => Colour::Rgba(*r, *g, *b, 1.0),
+            Colour::Grey(v) => Colour::Grey(*v),
+        };
+
+        for i in 0..self.pixels.len() {
+            if !self.drawn[i] || !self.depth[i].is_finite() {
+                continue;
+            }
+
+            let amount = fog.amount(self.camera.linear_depth(self.depth[i]), &self.camera);
+
+            self.pixels[i] = colour.over(&self.pixels[i], amount);
+        }
+    }
+}
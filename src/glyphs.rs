@@ -0,0 +1,133 @@
+// Picking characters by shape rather than just brightness. Along the edges of things a line
+// character that goes the same way as the edge looks far crisper than a block of the same
+// brightness, so wherever the brightness changes sharply from one cell to the next the direction it
+// changes in picks the character instead.
+
+use crate::device::*;
+
+// How colour_to_char's characters are picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlyphMode {
+    // Just by brightness.
+    Intensity,
+    // By the direction of edges where there are any, otherwise by brightness.
+    Shape,
+}
+
+// The character for an edge, given which way (on the screen, y down) the other side of it is.
+pub fn edge_glyph(towards: (f64, f64)) -> char {
+    // Cells are taller than they are wide, so stretch y out to get the real angle.
+    let (x, y) = (towards.0, towards.1 * CELL_ASPECT);
+
+    if x == 0.0 && y == 0.0 {
+        return '-';
+    }
+
+    // 0 is straight across, 90 straight up or down. The edge runs at right angles to this.
+    let angle = y.abs().atan2(x.abs()).to_degrees();
+
+    if angle < 30.0 {
+        '|'
+    }
+
+    else if angle > 60.0 {
+        // Along the bottom of something the line goes at the bottom of the cell.
+        if y > 0.0 { '_' } else { '-' }
+    }
+
+    else if (x > 0.0) == (y > 0.0) {
+        '/'
+    }
+
+    else {
+        '\\'
+    }
+}
+
+impl Device {
+    // Fills in glyphs for every cell on an edge that hasn't got one already. The edges are found
+    // with a Sobel filter over the brightness of each cell, with undrawn cells counting as black.
+    // Only the brighter side of an edge gets a character, so the lines are one cell thick.
+    pub fn shape_glyphs(&mut self) {
+        let (width, height) = self.dimensions;
+
+        let brightness: Vec<f64> = (0..width * height)
+            .map(|i| if self.drawn[i] { self.pixels[i].intensity() } else { 0.0 })
+            .collect();
+
+        // Brightness at (x, y), carrying on past the edges of the screen.
+        let at = |x: i64, y: i64| brightness[y.clamp(0, height as i64 - 1) as usize * width + x.clamp(0, width as i64 - 1) as usize];
+
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let i = y as usize * width + x as usize;
+
+                if !self.drawn[i] || self.glyphs[i].is_some() {
+                    continue;
+                }
+
+                let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+                    - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+                let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+                    - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+
+                // A step from 0 to 1 gives 4.
+                if (gx * gx + gy * gy).sqrt() / 4.0 < self.edge_threshold {
+                    continue;
+                }
+
+                // The gradient points towards the brighter side, so the other side of the edge is
+                // the opposite way. Skip this cell if it's the darker side itself, i.e. the next
+                // cell along the gradient is brighter than it.
+                let towards = (-gx, -gy);
+                let length = (towards.0 * towards.0 + towards.1 * towards.1).sqrt();
+                let (ox, oy) = ((towards.0 / length).round() as i64, (towards.1 / length).round() as i64);
+
+                if at(x - ox, y - oy) > at(x, y) {
+                    continue;
+                }
+
+                let mut glyph = edge_glyph(towards);
+
+                // The very tip of a rounded side, with the other side above and below as well.
+                if glyph == '|' && at(x, y - 1) < at(x, y) && at(x, y + 1) < at(x, y) {
+                    glyph = if towards.0 < 0.0 { '(' } else { ')' };
+                }
+
+                self.glyphs[i] = Some(glyph);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_objects::*;
+
+    #[test]
+    fn edges_are_one_cell_thick() {
+        let (width, height) = (12, 8);
+        let mut device = Device::with_dimensions(Camera::new(), Vec::new(), Colour::Grey(0.0), (width, height));
+
+        // Bright on the left, darker on the right.
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+
+                device.pixels[i] = Colour::Grey(if x < width / 2 { 0.9 } else { 0.3 });
+                device.drawn[i] = true;
+            }
+        }
+
+        device.shape_glyphs();
+
+        for y in 0..height {
+            let row: Vec<Option<char>> = device.glyphs[y * width..(y + 1) * width].to_vec();
+            let mut expected = vec![None; width];
+            expected[width / 2 - 1] = Some('|');
+
+            assert_eq!(row, expected, "row {}", y);
+        }
+    }
+}
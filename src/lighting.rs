@@ -0,0 +1,226 @@
+// How much light reaches a surface and what colour it ends up. Shared by the rasteriser and the
+// ray tracer so the two look the same.
+
+use crate::antialias::*;
+use crate::device::*;
+use crate::render_objects::*;
+use crate::shadow::*;
+use crate::structures::*;
+
+// Which lighting model surfaces are shaded with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightingModel {
+    // Ambient and diffuse only. Worked out once per face, so it's cheap.
+    Lambert,
+    // Ambient, diffuse and Blinn-Phong specular highlights, worked out for every cell.
+    BlinnPhong,
+}
+
+// Everything about how a frame is lit that doesn't depend on the surface: the model, the lights
+// and how much ambient light there is, and where it's being seen from.
+pub struct Illumination<'a> {
+    pub model: LightingModel,
+    pub lights: &'a [Light],
+    pub ambient: f64,
+    pub eye: Vector,
+}
+
+// The direction from pos towards a light, and how far away the light is. Directional lights are
+// infinitely far away.
+pub fn light_direction(light: &Light, pos: &Vector) -> (Vector, f64) {
+    match light.kind {
+        LightKind::Directional => (-light.direction, f64::INFINITY),
+        LightKind::Point | LightKind::Spot => {
+            let d = Vector::direction(light.pos.x - pos.x, light.pos.y - pos.y, light.pos.z - pos.z);
+
+            (d.normalised(), d.magnitude())
+        },
+    }
+}
+
+// How much of a light's strength gets to pos at all, from it getting dimmer with distance and
+// from the edges of a spotlight's cone. 0 to 1 for sensible attenuations.
+pub fn falloff(light: &Light, pos: &Vector) -> f64 {
+    let (to_light, distance) = light_direction(light, pos);
+
+    let attenuation = if distance.is_finite() {
+        let [constant, linear, quadratic] = light.attenuation;
+
+        1.0 / (constant + linear * distance + quadratic * distance * distance).max(f64::EPSILON)
+    }
+
+    else {
+        1.0
+    };
+
+    if light.kind != LightKind::Spot {
+        return attenuation;
+    }
+
+    // Full strength inside the inner cone, nothing outside the outer one and fading in between.
+    let cos = Vector::dot_product(&-to_light, &light.direction);
+    let (outer, inner) = (light.cone.cos(), light.inner_cone.min(light.cone).cos());
+
+    let spot = if inner - outer > f64::EPSILON {
+        ((cos - outer) / (inner - outer)).clamp(0.0, 1.0)
+    }
+
+    else if cos >= outer {
+        1.0
+    }
+
+    else {
+        0.0
+    };
+
+    attenuation * spot
+}
+
+// The colour of a point on a surface seen from eye. visibility(i) is how much of lights[i] isn't
+// in shadow, from 0 to 1.
+pub fn shade<F>(illumination: &Illumination, material: &Material, pos: &Vector, normal: &Vector, visibility: F) -> Colour
+where
+    F: Fn(usize) -> f64,
+{
+    let (kr, kg, kb, ka) = material.colour.to_rgba();
+    let eye = &illumination.eye;
+    let to_eye = Vector::direction(eye.x - pos.x, eye.y - pos.y, eye.z - pos.z).normalised();
+
+    let base = illumination.ambient * material.ambient;
+    let mut diffuse = (base, base, base);
+    let mut specular = (0.0, 0.0, 0.0);
+
+    for (i, l) in illumination.lights.iter().enumerate() {
+        let (to_light, _) = light_direction(l, pos);
+        let n_dot_l = Vector::dot_product(normal, &to_light);
+
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+
+        let strength = l.intensity * falloff(l, pos) * visibility(i);
+
+        if strength <= 0.0 {
+            continue;
+        }
+
+        let (lr, lg, lb, _) = l.colour.to_rgba();
+        let d = n_dot_l * strength;
+
+        diffuse = (diffuse.0 + lr * d, diffuse.1 + lg * d, diffuse.2 + lb * d);
+
+        if illumination.model == LightingModel::BlinnPhong && material.specular > 0.0 {
+            // The half vector, halfway between the light and the eye. The closer it is to the
+            // normal, the closer the surface is to reflecting the light straight at the eye.
+            let half = Vector::direction(to_light.x + to_eye.x, to_light.y + to_eye.y, to_light.z + to_eye.z).normalised();
+            let s = Vector::dot_product(normal, &half).max(0.0).powf(material.shininess) * material.specular * strength;
+
+            specular = (specular.0 + lr * s, specular.1 + lg * s, specular.2 + lb * s);
+        }
+    }
+
+    let r = (kr * diffuse.0 + specular.0).clamp(0.0, 1.0);
+    let g = (kg * diffuse.1 + specular.1).clamp(0.0, 1.0);
+    let b = (kb * diffuse.2 + specular.2).clamp(0.0, 1.0);
+
+    match material.colour {
+        Colour::Rgba(..) => Colour::Rgba(r, g, b, ka),
+        Colour::Grey(_) => Colour::Grey(Colour::Rgba(r, g, b, 1.0).intensity()),
+    }
+}
+
+impl Device {
+    // Lights every cell of the frame that has a face in it individually, rather than a face at a
+    // time. Needed for specular highlights and shadows. Uses the ID buffer to know which face is
+    // in each cell, and id_shading for the material and world space normal of each face in it.
+    // With antialiasing the samples are lit instead and averaged: every one of them when
+    // supersampling, or each face in the cell once when multisampling.
+    pub fn shade_cells(&mut self, lights: &[Light], maps: &[Option<ShadowMap>], view_projection: &Matrix) {
+        let inverse = match view_projection.inverse() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+
+        let (width, height) = self.dimensions;
+        let (bias, pcf) = (self.shadow_bias, self.shadow_pcf);
+        let shading = &self.id_shading;
+        let illumination = Illumination {
+            model: self.lighting,
+            lights,
+            ambient: self.ambient,
+            eye: self.camera.pos,
+        };
+
+        // The colour of face id at (x, y) on a screen of the given size, with the given depth.
+        let light = |x: f64, y: f64, depth: f64, viewport: (usize, usize), id: u32| {
+            // Back from the screen to the world, the opposite of what the rasteriser does.
+            let ndc = Vector::from(
+                (x + 0.5) / viewport.0 as f64 * 2.0 - 1.0,
+                1.0 - (y + 0.5) / viewport.1 as f64 * 2.0,
+                depth,
+                1.0,
+            );
+            let pos = (inverse * ndc).homogenised();
+            let (material, normal) = &shading[id as usize - 1];
+
+            shade(&illumination, material, &pos, normal, |l| {
+                maps.get(l)
+                    .and_then(|m| m.as_ref())
+                    .map_or(1.0, |m| m.visibility(&pos, normal, bias, pcf))
+            })
+        };
+
+        let samples = self.sample_buffer.take();
+        let n = self.antialiasing.samples();
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let id = self.ids[i];
+
+                if id == 0 {
+                    continue;
+                }
+
+                let tile = match &samples {
+                    Some(tile) => tile,
+                    None => {
+                        self.pixels[i] = light(x as f64, y as f64, self.depth[i], self.dimensions, id);
+                        continue;
+                    },
+                };
+
+                // Faces already lit in this cell, when multisampling.
+                let mut lit: Vec<(u32, Colour)> = Vec::new();
+
+                let colour = Colour::average((0..n * n).map(|k| {
+                    let (sx, sy) = (x * n + k % n, y * n + k / n);
+                    let s = sy * tile.viewport.0 + sx;
+                    let sample_id = tile.ids[s];
+
+                    if sample_id == 0 {
+                        tile.pixels[s].clone()
+                    }
+
+                    else if let Some((_, c)) = lit.iter().find(|(l, _)| *l == sample_id) {
+                        c.clone()
+                    }
+
+                    else {
+                        let c = light(sx as f64, sy as f64, tile.depth[s], tile.viewport, sample_id);
+
+                        if let Antialiasing::Multisample(_) = self.antialiasing {
+                            lit.push((sample_id, c.clone()));
+                        }
+
+                        c
+                    }
+                }));
+
+                self.pixels[i] = colour.unwrap();
+            }
+        }
+
+        self.sample_buffer = samples;
+    }
+}
@@ -3,25 +3,120 @@ extern crate term_size;
 mod structures;
 mod render_objects;
 mod device;
+mod stats;
+mod overlay;
+mod raster;
+mod target;
+mod simd;
+mod scene;
+mod pipeline;
+mod bounds;
+mod ray;
+mod bvh;
+mod picking;
+mod raytrace;
+mod shadow;
+mod lighting;
+mod shader;
+mod toon;
+mod glyphs;
+mod antialias;
+mod postprocess;
+mod fog;
 
 use structures::*;
 use render_objects::*;
 use device::*;
+use target::*;
+use scene::*;
+use lighting::*;
+use glyphs::*;
+use antialias::*;
+use postprocess::*;
+use fog::*;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    let mut m = Mesh::from_file(String::from("objects/donut.obj"), Vector::from(2.0, 3.0, 4.0, 1.0), Vector::new()).unwrap();
+    // Loaded once, and shared by every mesh that uses it.
+    let donut_geometry = Arc::new(Geometry::from_file(String::from("objects/donut.obj")).unwrap());
     let mut angle = 0.01f64;
 
     let camera = Camera::from(Vector::from(3.0, 4.0, 5.0, 1.0), Vector::new(), Vector::from(0.0, 1.0, 0.0, 0.0));
-    let mut device = Device::new(camera, vec![m.clone()], Colour::Grey(0.0));
+    let mut device = Device::new(camera, Vec::new(), Colour::Grey(0.0));
+    let donut = device.scene.add_node("donut", None, Transform::new());
+    let mut donut_mesh = Mesh::new(Arc::clone(&donut_geometry));
+    let mut shiny = Material::new(Colour::Grey(0.8));
+    shiny.specular = 0.6;
+    shiny.shininess = 24.0;
+    donut_mesh.material = Some(shiny);
+    device.scene.nodes[donut].mesh = Some(donut_mesh);
+    // Something for the donut to cast a shadow on.
+    let ground = device.scene.add_node("ground", None, Transform::from(Vector::direction(0.0, -1.5, 0.0), Quaternion::identity(), Vector::direction(1.0, 1.0, 1.0)));
+    let mut plane = Mesh::new(Arc::new(Geometry::plane(String::from("ground"), 6.0)));
+    plane.material = Some(Material::new(Colour::Grey(0.6)));
+    device.scene.nodes[ground].mesh = Some(plane);
+    device.shadows = true;
+    device.lighting = LightingModel::BlinnPhong;
 
+    device.lights.push(Light::directional(Vector::direction(-1.0, -2.0, -1.0), Colour::Grey(1.0)));
+
+    device.show_stats = std::env::args().any(|a| a == "--stats");
+
+    if std::env::args().any(|a| a == "--ssaa") {
+        device.antialiasing = Antialiasing::Supersample(4);
+    }
+
+    if std::env::args().any(|a| a == "--msaa") {
+        device.antialiasing = Antialiasing::Multisample(4);
+    }
+
+    if std::env::args().any(|a| a == "--shape-glyphs") {
+        device.glyph_mode = GlyphMode::Shape;
+    }
+
+    // The donut's about 7 away and the far corner of the ground about 11, so this fades the back of
+    // the ground out most of the way.
+    if std::env::args().any(|a| a == "--fog") {
+        let mut fog = Fog::new(FogMode::Linear);
+        fog.range = Some((6.0, 13.0));
+        device.post_processing.push(PostProcess::Fog(fog));
+    }
+
+    let dump_stats = std::env::args().any(|a| a == "--dump-stats");
+
+    if std::env::args().any(|a| a == "--ray-trace") {
+        device.renderer = Renderer::RayTracer;
+    }
+
+    for a in std::env::args() {
+        match a.as_str() {
+            "--wireframe" => device.mode = RenderMode::Wireframe,
+            "--hidden-line" => device.mode = RenderMode::HiddenLine,
+            "--solid" => device.mode = RenderMode::Solid,
+            "--normals" => device.mode = RenderMode::Normals,
+            "--depth" => device.mode = RenderMode::Depth,
+            "--toon" => device.mode = RenderMode::Toon,
+            // Only make a difference with --wireframe.
+            "--dashed" => device.wireframe_style = LineStyle::Dashed(2, 1),
+            "--dotted" => device.wireframe_style = LineStyle::Pattern(0x5555),
+            _ => {}
+        }
+    }
+
+    device.clear_screen();
 
     loop {
-        device.clear_screen();
-        device.test_render();
-        device.meshes[0] = m.clone()*Matrix::rotation(angle, Vector::from(1.0, 1.0, 1.0, 0.0));
+        device.clear();
+        device.render();
+        device.present();
+
+        if dump_stats {
+            eprintln!("{}", device.stats.dump());
+        }
+
+        device.scene.modify(donut, |t| t.rotation = Quaternion::from_axis_angle(Vector::from(1.0, 1.0, 1.0, 0.0), angle));
         thread::sleep(Duration::from_millis(33));
 
         angle += 0.2f64;
@@ -0,0 +1,227 @@
+// 2D drawing on top of the rendered scene: text, boxes, circles and lines. These don't go through
+// the colour framebuffer at all, they're written straight into the overlay as characters, which
+// is then laid over the 3D output when the frame is presented. Coordinates here are in character
+// cells, x across and y down, and are allowed to go off the edge of the screen.
+
+use crate::device::*;
+use crate::target::*;
+
+// Which set of box drawing characters to draw rectangles with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum BoxStyle {
+    Light,   // ┌─┐
+    Heavy,   // ┏━┓
+    Double,  // ╔═╗
+    Rounded, // ╭─╮
+    Ascii,   // +-+ for terminals that can't do any of the above
+}
+
+impl BoxStyle {
+    // (top left, top right, bottom left, bottom right, horizontal, vertical)
+    fn glyphs(self) -> (char, char, char, char, char, char) {
+        match self {
+            BoxStyle::Light => ('┌', '┐', '└', '┘', '─', '│'),
+            BoxStyle::Heavy => ('┏', '┓', '┗', '┛', '━', '┃'),
+            BoxStyle::Double => ('╔', '╗', '╚', '╝', '═', '║'),
+            BoxStyle::Rounded => ('╭', '╮', '╰', '╯', '─', '│'),
+            BoxStyle::Ascii => ('+', '+', '+', '+', '-', '|'),
+        }
+    }
+}
+
+impl Device {
+    // Put a single character into the overlay. Anything off screen is ignored.
+    pub fn draw_char(&mut self, x: i32, y: i32, c: char) {
+        let (width, height) = self.dimensions;
+
+        if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+            return;
+        }
+
+        self.overlay[y as usize * width + x as usize] = Some(c);
+    }
+
+    // Write a line of text starting at (x, y). Newlines start again on the next row from x.
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str) {
+        for (row, line) in text.lines().enumerate() {
+            for (col, c) in line.chars().enumerate() {
+                self.draw_char(x + col as i32, y + row as i32, c);
+            }
+        }
+    }
+
+    // The outline of a rectangle with its top left corner at (x, y), including the border.
+    pub fn draw_rect(&mut self, x: i32, y: i32, width: usize, height: usize, style: BoxStyle) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let (tl, tr, bl, br, h, v) = style.glyphs();
+        let (x1, y1) = (x + width as i32 - 1, y + height as i32 - 1);
+
+        for i in (x + 1)..x1 {
+            self.draw_char(i, y, h);
+            self.draw_char(i, y1, h);
+        }
+
+        for j in (y + 1)..y1 {
+            self.draw_char(x, j, v);
+            self.draw_char(x1, j, v);
+        }
+
+        self.draw_char(x, y, tl);
+        self.draw_char(x1, y, tr);
+        self.draw_char(x, y1, bl);
+        self.draw_char(x1, y1, br);
+    }
+
+    // Fill a rectangle with one character. Filling with ' ' is a handy way to blank out a bit of
+    // the scene behind a label.
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: usize, height: usize, c: char) {
+        for j in y..(y + height as i32) {
+            for i in x..(x + width as i32) {
+                self.draw_char(i, j, c);
+            }
+        }
+    }
+
+    // A box with some text inside it, sized to fit.
+    pub fn draw_label(&mut self, x: i32, y: i32, text: &str, style: BoxStyle) {
+        let width = text.lines().map(|l| l.chars().count()).max().unwrap_or(0);
+        let height = text.lines().count();
+
+        self.fill_rect(x, y, width + 2, height + 2, ' ');
+        self.draw_rect(x, y, width + 2, height + 2, style);
+        self.draw_text(x + 1, y + 1, text);
+    }
+
+    // An ellipse centred on (cx, cy) with radii rx across and ry down, in cells.
+    #[allow(dead_code)]
+    pub fn draw_ellipse(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, c: char) {
+        // Step round the edge finely enough that neighbouring points are never more than a cell
+        // apart. Anything too big for that to be worth it (most of it is off the screen) takes
+        // fewer steps, and the points are joined up with lines instead.
+        let (width, height) = self.dimensions;
+        let most = (4 * (width + height)).max(8) as f64;
+        let steps = (rx.max(ry) * std::f64::consts::PI * 4.0).ceil().clamp(8.0, most) as usize;
+        let point = |i: usize| {
+            let t = i as f64 / steps as f64 * std::f64::consts::PI * 2.0;
+
+            ((cx + rx * t.cos()).round() as i32, (cy + ry * t.sin()).round() as i32)
+        };
+
+        for i in 0..steps {
+            self.draw_overlay_line(point(i), point(i + 1), Some(c));
+        }
+    }
+
+    // A circle with a radius of r rows, corrected for the shape of the cells.
+    #[allow(dead_code)]
+    pub fn draw_circle(&mut self, cx: f64, cy: f64, r: f64, c: char) {
+        self.draw_ellipse(cx, cy, r * CELL_ASPECT, r, c);
+    }
+
+    // Join up a list of points with straight lines. If c is None, each segment picks whichever of
+    // - | / \ best matches its direction.
+    #[allow(dead_code)]
+    pub fn draw_polyline(&mut self, points: &[(i32, i32)], c: Option<char>) {
+        for pair in points.windows(2) {
+            self.draw_overlay_line(pair[0], pair[1], c);
+        }
+    }
+
+    fn draw_overlay_line(&mut self, p1: (i32, i32), p2: (i32, i32), c: Option<char>) {
+        let glyph = c.unwrap_or_else(|| {
+            // Again, cells are twice as tall as they are wide. Worked out before clipping so the
+            // part on the screen matches the whole line.
+            let (w, h) = (p2.0 as f64 - p1.0 as f64, (p2.1 as f64 - p1.1 as f64) * CELL_ASPECT);
+
+            if h.abs() < w.abs() * 0.5 {
+                '-'
+            }
+
+            else if h.abs() > w.abs() * 2.0 {
+                '|'
+            }
+
+            else if (w > 0.0) == (h > 0.0) {
+                '\\'
+            }
+
+            else {
+                '/'
+            }
+        });
+
+        // Only the part on the screen is stepped along, so far off points don't take forever (or
+        // overflow).
+        let (p1, p2) = match self.clip_line(p1, p2) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+
+        let dx = (p2.0 - p1.0).abs();
+        let dy = -(p2.1 - p1.1).abs();
+        let sx = if p1.0 < p2.0 { 1 } else { -1 };
+        let sy = if p1.1 < p2.1 { 1 } else { -1 };
+
+        let (mut x, mut y) = p1;
+        let mut err = dx + dy;
+
+        loop {
+            self.draw_char(x, y, glyph);
+
+            if x == p2.0 && y == p2.1 {
+                break;
+            }
+
+            let e2 = 2 * err;
+
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_objects::*;
+    use crate::structures::*;
+
+    fn device() -> Device {
+        let camera = Camera::from(Vector::from(0.0, 0.0, 5.0, 1.0), Vector::new(), Vector::from(0.0, 1.0, 0.0, 0.0));
+
+        Device::with_dimensions(camera, Vec::new(), Colour::Grey(0.0), (40, 20))
+    }
+
+    // Lines and ellipses going miles off the screen only draw the part that's on it, rather than
+    // overflowing or stepping along the rest.
+    #[test]
+    fn far_off_shapes_are_clipped() {
+        let mut d = device();
+
+        d.draw_polyline(&[(i32::MIN, i32::MIN), (i32::MAX, i32::MAX)], None);
+        assert_eq!(d.overlay[0], Some('\\'));
+        assert!(d.overlay.iter().all(|c| c.is_none() || *c == Some('\\')));
+
+        let mut d = device();
+
+        d.draw_polyline(&[(-1000, 10), (1000, 10)], Some('#'));
+        assert!((0..40).all(|x| d.overlay[10 * 40 + x] == Some('#')));
+        assert_eq!(d.overlay.iter().filter(|c| c.is_some()).count(), 40);
+
+        let mut d = device();
+
+        d.draw_ellipse(20.0, 1e9, 1e12, 1e9, '*');
+        assert!(d.overlay.iter().any(|c| c.is_some()));
+    }
+}
@@ -0,0 +1,141 @@
+// Finding out what's under a cell on the screen, e.g. for clicking on things. Either a ray is fired
+// from the camera through the cell and checked against everything, or if the ID buffer was filled
+// in while rendering the answer can just be looked up.
+
+use crate::bvh::*;
+use crate::device::*;
+use crate::ray::*;
+use crate::structures::*;
+
+// What's under a cell. distance is from the camera, along the ray through the centre of the cell,
+// and barycentric is the weight of each of the face's vertices at the point that was hit.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct Pick {
+    pub mesh: MeshId,
+    pub face: usize,
+    pub barycentric: [f64; 3],
+    pub distance: f64,
+}
+
+impl Device {
+    // The ray from the camera through the centre of the cell at (x, y), x across and y down.
+    pub fn cell_ray(&self, x: usize, y: usize) -> Ray {
+        let (width, height) = self.dimensions;
+        // The opposite of pipeline::to_screen.
+        let ndc_x = (x as f64 + 0.5) / width as f64 * 2.0 - 1.0;
+        let ndc_y = 1.0 - (y as f64 + 0.5) / height as f64 * 2.0;
+
+        self.camera.ray(ndc_x, ndc_y, self.aspect())
+    }
+
+    // The closest face under the cell at (x, y), if there is one. Uses the ID buffer if it was
+    // filled in for the last frame and there's something in it at (x, y), and casts a ray against
+    // everything otherwise.
+    #[allow(dead_code)]
+    pub fn pick(&mut self, x: usize, y: usize) -> Option<Pick> {
+        let (width, height) = self.dimensions;
+
+        if x >= width || y >= height {
+            return None;
+        }
+
+        self.scene.update();
+
+        let ray = self.cell_ray(x, y);
+        let meshes = instances(&self.meshes, &self.scene);
+
+        // The ID buffer already knows which face it is, so only that one needs the ray checking
+        // against it to work out exactly where it was hit. Only rasterise fills it in though, so
+        // after the shader modes (normals and depth) or the ray tracer every cell is 0, and the ray
+        // has to be cast against everything anyway.
+        let id = if self.id_buffer { self.ids[y * width + x] } else { 0 };
+
+        if id != 0 {
+            let (mesh, face) = self.id_faces[id as usize - 1];
+            let (_, world, m) = meshes.iter().find(|(i, _, _)| *i == mesh)?;
+            let model = world * m.model_matrix();
+            let f = &m.geometry.faces[face];
+            let v = &m.geometry.vertices;
+
+            // The centre of the cell might just miss the face even though the face covers the
+            // cell, so this is done without any of the usual range checks.
+            let (a, b, c) = (model * v[f.vertices[0]], model * v[f.vertices[1]], model * v[f.vertices[2]]);
+            let (distance, barycentric) = ray.intersect_plane(&a, &b, &c)?;
+
+            return Some(Pick { mesh, face, barycentric, distance });
+        }
+
+        let pairs: Vec<_> = meshes.iter().map(|(_, world, m)| (*world, *m)).collect();
+        let hit = SceneBvh::build(&pairs).intersect_ray(&ray, f64::INFINITY)?;
+
+        // Anything outside the near and far planes wouldn't have been drawn. (Strictly, something
+        // further back could be showing through behind a face cut off by the near plane, but
+        // that's not worth the bother.)
+        let depth = Vector::dot_product(&(ray.at(hit.hit.t) - self.camera.pos), &self.camera.forward());
+
+        if depth < self.camera.near || depth > self.camera.far {
+            return None;
+        }
+
+        Some(Pick {
+            mesh: meshes[hit.instance].0,
+            face: hit.hit.face,
+            barycentric: hit.hit.barycentric,
+            distance: hit.hit.t,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_objects::*;
+    use std::sync::Arc;
+
+    // Looking up the ID buffer and casting a ray should find the same thing, anywhere the centre
+    // of the cell is definitely on the face in the ID buffer.
+    #[test]
+    fn id_buffer_and_ray_agree() {
+        let camera = Camera::from(Vector::from(3.0, 4.0, 5.0, 1.0), Vector::new(), Vector::from(0.0, 1.0, 0.0, 0.0));
+        let mut device = Device::with_dimensions(camera, Vec::new(), Colour::Grey(0.0), (80, 40));
+        let donut = Arc::new(Geometry::from_file(String::from("objects/donut.obj")).unwrap());
+        let ground = Arc::new(Geometry::plane(String::from("ground"), 6.0));
+
+        device.meshes.push(Mesh::from(donut, Vector::point(0.0, 0.5, 0.0), Quaternion::identity()));
+        device.meshes.push(Mesh::from(ground, Vector::point(0.0, -0.5, 0.0), Quaternion::identity()));
+        device.id_buffer = true;
+        device.mode = RenderMode::Solid;
+        device.render();
+
+        let (width, height) = device.dimensions;
+        let mut checked = 0;
+
+        for y in 0..height - 1 {
+            for x in 0..width - 1 {
+                let i = y * width + x;
+                let id = device.ids[i];
+
+                // The rasteriser samples the corners of cells and the ray goes through the middle,
+                // so only cells with the same face at all four corners are certain.
+                if id == 0 || [i + 1, i + width, i + width + 1].iter().any(|j| device.ids[*j] != id) {
+                    continue;
+                }
+
+                device.id_buffer = true;
+                let looked_up = device.pick(x, y).unwrap();
+                device.id_buffer = false;
+                let cast = device.pick(x, y).unwrap();
+
+                assert_eq!(looked_up.mesh, cast.mesh, "({}, {})", x, y);
+                assert_eq!(looked_up.face, cast.face, "({}, {})", x, y);
+                assert!((looked_up.distance - cast.distance).abs() < 1e-9, "({}, {})", x, y);
+                assert!(looked_up.barycentric.iter().zip(&cast.barycentric).all(|(a, b)| (a - b).abs() < 1e-9), "({}, {})", x, y);
+
+                checked += 1;
+            }
+        }
+
+        assert!(checked > 100, "only {} cells checked", checked);
+    }
+}
@@ -0,0 +1,105 @@
+// The geometry side of rendering: getting from a mesh's own coordinates to points on the screen.
+// Vertices go model -> world -> camera -> clip space through the MVP matrix, get clipped against
+// the near plane, then divided through by w and stretched out over the screen.
+
+use crate::structures::*;
+
+// Normals can't just be multiplied by the model matrix, since any non-uniform scaling would stop
+// them being perpendicular to the surface. The inverse transpose keeps them right. Multiply
+// directions (w = 0) by it and normalise afterwards.
+pub fn normal_matrix(model: &Matrix) -> Matrix {
+    match model.inverse() {
+        Some(inverse) => inverse.transpose(),
+        // Squashed flat, so there's no right answer. Better than nothing.
+        None => *model,
+    }
+}
+
+pub fn transform_normal(normal_matrix: &Matrix, normal: Vector) -> Vector {
+    let n = normal_matrix * Vector::direction(normal.x, normal.y, normal.z);
+
+    Vector::direction(n.x, n.y, n.z).normalised()
+}
+
+// Which of the six clip planes each point is outside of, one bit per plane. If all three points
+// of a triangle share a bit the whole thing is off screen.
+pub fn outcode(v: &Vector) -> u8 {
+    let mut code = 0;
+
+    if v.x < -v.w { code |= 1; }
+    if v.x > v.w { code |= 2; }
+    if v.y < -v.w { code |= 4; }
+    if v.y > v.w { code |= 8; }
+    if v.z < -v.w { code |= 16; }
+    if v.z > v.w { code |= 32; }
+
+    code
+}
+
+// Cut a clip space triangle against the near plane (z = -w). Anything behind the camera would
+// come out of the perspective divide upside down, so this has to happen before it. Gives back
+// nothing, the same triangle, or the one or two triangles that are left.
+pub fn clip_near(triangle: [Vector; 3]) -> Vec<[Vector; 3]> {
+    clip_near_weights(&triangle)
+        .into_iter()
+        .map(|piece| piece.map(|w| weighted(&triangle, w)))
+        .collect()
+}
+
+// The same as clip_near, but each corner of the triangles left is given as the weight of each of
+// the original three points, so anything else at the corners (e.g. shader varyings) can be cut up
+// in the same way.
+pub fn clip_near_weights(triangle: &[Vector; 3]) -> Vec<[[f64; 3]; 3]> {
+    let distance = |v: &Vector| v.z + v.w;
+    let corners = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    let mut polygon: Vec<[f64; 3]> = Vec::with_capacity(4);
+
+    // Sutherland-Hodgman with just the one plane.
+    for i in 0..3 {
+        let (a, b) = (corners[i], corners[(i + 1) % 3]);
+        let (da, db) = (distance(&triangle[i]), distance(&triangle[(i + 1) % 3]));
+
+        if da >= 0.0 {
+            polygon.push(a);
+        }
+
+        if (da >= 0.0) != (db >= 0.0) {
+            let t = da / (da - db);
+
+            polygon.push([a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]);
+        }
+    }
+
+    let mut triangles = Vec::new();
+
+    for i in 1..polygon.len().saturating_sub(1) {
+        triangles.push([polygon[0], polygon[i], polygon[i + 1]]);
+    }
+
+    triangles
+}
+
+// The point with the given weight of each of a triangle's corners.
+pub fn weighted(triangle: &[Vector; 3], w: [f64; 3]) -> Vector {
+    let [a, b, c] = triangle;
+
+    Vector::from(
+        a.x * w[0] + b.x * w[1] + c.x * w[2],
+        a.y * w[0] + b.y * w[1] + c.y * w[2],
+        a.z * w[0] + b.z * w[1] + c.z * w[2],
+        a.w * w[0] + b.w * w[1] + c.w * w[2],
+    )
+}
+
+// From clip space to (x, y, depth) on a screen of the given size, with y going down. Pixels are
+// sampled at whole numbers, so the edges of the screen are half a pixel either side of them.
+// Depth is the normalised device z, so -1 at the near plane and 1 at the far one.
+pub fn to_screen(v: &Vector, viewport: (usize, usize)) -> (f64, f64, f64) {
+    let ndc = v.homogenised();
+
+    (
+        (ndc.x + 1.0) * 0.5 * viewport.0 as f64 - 0.5,
+        (1.0 - ndc.y) * 0.5 * viewport.1 as f64 - 0.5,
+        ndc.z,
+    )
+}
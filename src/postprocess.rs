@@ -0,0 +1,162 @@
+// Post-processing: passes over the finished framebuffer, after everything's been drawn but before
+// the colours are turned into characters. They're run in the order they're listed in
+// Device::post_processing, so e.g. blurring before or after a vignette gives different results.
+
+use crate::device::*;
+use crate::fog::*;
+use crate::render_objects::*;
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub enum PostProcess {
+    // Raises every channel to the power of 1 / gamma. Over 1 brightens the darker parts.
+    Gamma(f64),
+    // Squeezes any brightness into 0 to 1 with 1 - e^(-exposure * v), so bright things don't all
+    // end up as the same character.
+    ToneMap(f64),
+    // Fades things into the background the further away they are (see fog.rs).
+    Fog(Fog),
+    // Darkens towards the edges of the screen, by up to strength in the corners.
+    Vignette(f64),
+    // Box blur, averaging everything up to radius cells away across. Cells are taller than they
+    // are wide, so it's less far up and down.
+    Blur(usize),
+    // Unsharp masking: pushes each cell away from the average of it and its neighbours by amount.
+    Sharpen(f64),
+    // (contrast, brightness). Contrast stretches everything out from the middle grey, and
+    // brightness is added on afterwards.
+    ContrastBrightness(f64, f64),
+}
+
+// Does f to each channel of a colour, leaving alpha alone.
+fn map_channels<F: Fn(f64) -> f64>(colour: &Colour, f: F) -> Colour {
+    match *colour {
+        Colour::Rgba(r, g, b, a) => Colour::Rgba(f(r), f(g), f(b), a),
+        Colour::Grey(v) => Colour::Grey(f(v)),
+    }
+}
+
+// Does f to each pair of channels of two colours. Comes out in the same colour space as a.
+fn zip_channels<F: Fn(f64, f64) -> f64>(a: &Colour, b: &Colour, f: F) -> Colour {
+    match (a, b) {
+        (Colour::Grey(x), Colour::Grey(y)) => Colour::Grey(f(*x, *y)),
+        (Colour::Grey(x), _) => Colour::Grey(f(*x, b.intensity())),
+
+        (Colour::Rgba(..), _) => {
+            let (r1, g1, b1, a1) = a.to_rgba();
+            let (r2, g2, b2, _) = b.to_rgba();
+
+            Colour::Rgba(f(r1, r2), f(g1, g2), f(b1, b2), a1)
+        },
+    }
+}
+
+impl Device {
+    // Runs every pass in post_processing over the framebuffer.
+    pub fn post_process(&mut self) {
+        for pass in self.post_processing.clone() {
+            match pass {
+                PostProcess::Gamma(gamma) => {
+                    let power = 1.0 / gamma.max(f64::EPSILON);
+
+                    self.map_drawn(|c| map_channels(c, |v| v.max(0.0).powf(power)));
+                },
+
+                PostProcess::ToneMap(exposure) => {
+                    self.map_drawn(|c| map_channels(c, |v| 1.0 - (-exposure * v.max(0.0)).exp()));
+                },
+
+                PostProcess::Fog(fog) => self.fog(&fog),
+
+                PostProcess::Vignette(strength) => {
+                    let (width, height) = self.dimensions;
+
+                    for y in 0..height {
+                        for x in 0..width {
+                            let i = y * width + x;
+
+                            if !self.drawn[i] {
+                                continue;
+                            }
+
+                            // How far from the middle, from 0 there to 1 in the corners.
+                            let dx = (x as f64 + 0.5) / width as f64 * 2.0 - 1.0;
+                            let dy = (y as f64 + 0.5) / height as f64 * 2.0 - 1.0;
+                            let r = (dx * dx + dy * dy) / 2.0;
+
+                            self.pixels[i] = self.pixels[i].scaled((1.0 - strength * r).max(0.0));
+                        }
+                    }
+                },
+
+                PostProcess::Blur(radius) => {
+                    let (pixels, drawn) = self.blurred(radius);
+
+                    self.pixels = pixels;
+                    self.drawn = drawn;
+                },
+
+                PostProcess::Sharpen(amount) => {
+                    let (blurred, _) = self.blurred(1);
+
+                    for (i, b) in blurred.iter().enumerate() {
+                        if self.drawn[i] {
+                            self.pixels[i] = zip_channels(&self.pixels[i], b, |v, m| (v + amount * (v - m)).clamp(0.0, 1.0));
+                        }
+                    }
+                },
+
+                PostProcess::ContrastBrightness(contrast, brightness) => {
+                    self.map_drawn(|c| map_channels(c, |v| ((v - 0.5) * contrast + 0.5 + brightness).clamp(0.0, 1.0)));
+                },
+            }
+        }
+    }
+
+    // Replaces every drawn pixel with f of it.
+    fn map_drawn<F: Fn(&Colour) -> Colour>(&mut self, f: F) {
+        for (p, drawn) in self.pixels.iter_mut().zip(&self.drawn) {
+            if *drawn {
+                *p = f(p);
+            }
+        }
+    }
+
+    // The framebuffer box blurred, one way and then the other. Undrawn cells count as the
+    // background, and anything blurred into them gets drawn.
+    fn blurred(&self, radius: usize) -> (Vec<Colour>, Vec<bool>) {
+        let (width, height) = self.dimensions;
+        let vertical = (radius as f64 / CELL_ASPECT).round() as usize;
+
+        let pixels: Vec<Colour> = (0..width * height)
+            .map(|i| if self.drawn[i] { self.pixels[i].clone() } else { self.background.clone() })
+            .collect();
+
+        // One pass, either across or down, averaging up to radius cells either side.
+        let pass = |pixels: &[Colour], drawn: &[bool], radius: usize, across: bool| {
+            let mut out_pixels = pixels.to_vec();
+            let mut out_drawn = drawn.to_vec();
+
+            for y in 0..height {
+                for x in 0..width {
+                    let (along, length) = if across { (x, width) } else { (y, height) };
+                    let from = along.saturating_sub(radius);
+                    let to = (along + radius).min(length - 1);
+
+                    let cells: Vec<usize> = (from..=to)
+                        .map(|a| if across { y * width + a } else { a * width + x })
+                        .collect();
+
+                    out_pixels[y * width + x] = Colour::average(cells.iter().map(|j| pixels[*j].clone())).unwrap();
+                    out_drawn[y * width + x] = cells.iter().any(|j| drawn[*j]);
+                }
+            }
+
+            (out_pixels, out_drawn)
+        };
+
+        let (pixels, drawn) = pass(&pixels, &self.drawn, radius, true);
+
+        pass(&pixels, &drawn, vertical, false)
+    }
+}
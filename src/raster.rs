@@ -0,0 +1,298 @@
+// The triangle rasteriser. This works out which pixels a screen space triangle covers and hands
+// each one to a callback along with its barycentric coordinates, so the same code can be used for
+// filling, depth and whatever else needs interpolating across a triangle.
+//
+// Vertices are snapped to a fixed point grid and the edge functions are done in integers. That way
+// stepping from one pixel to the next is exact, and the same pixel always gets the same answer no
+// matter which order (or which thread) it's visited in.
+
+use crate::render_objects::*;
+
+// Bits of sub-pixel precision.
+const SUB_BITS: u32 = 4;
+const SUB: i64 = 1 << SUB_BITS;
+
+// Triangles are walked in square tiles of this many pixels. Whole tiles can be skipped or filled
+// without testing each pixel.
+pub const TILE_SIZE: usize = 8;
+
+// Anything further off screen than this can't be converted to fixed point without the edge
+// functions overflowing.
+const MAX_COORD: f64 = (1 << 24) as f64;
+
+// (min x, min y, max x, max y) of an area of the screen in pixels. The max is exclusive.
+pub type Rect = (usize, usize, usize, usize);
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    // How much the edge function changes going one pixel right and one pixel down
+    step_x: i64,
+    step_y: i64,
+    // The value of the edge function at pixel (0, 0)
+    origin: i64,
+    // Subtracted from pixels exactly on the edge that don't own it (see top_left)
+    bias: i64,
+}
+
+impl Edge {
+    fn from(a: (i64, i64), b: (i64, i64)) -> Edge {
+        // E(p) = (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+
+        // Top-left fill rule: a pixel lying exactly on an edge shared by two triangles only gets
+        // drawn by one of them. With y going down the screen, top edges are flat and go right,
+        // and left edges go up.
+        let top_left = (dy == 0 && dx > 0) || dy < 0;
+
+        Edge {
+            step_x: -dy * SUB,
+            step_y: dx * SUB,
+            origin: dx * (-a.1) - dy * (-a.0),
+            bias: if top_left { 0 } else { -1 },
+        }
+    }
+
+    fn at(&self, x: usize, y: usize) -> i64 {
+        self.origin + self.step_x * x as i64 + self.step_y * y as i64
+    }
+}
+
+fn edge_function(a: (i64, i64), b: (i64, i64), p: (i64, i64)) -> i64 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+// Calls f(x, y, barycentric) for every pixel inside the triangle and inside bounds. The
+// barycentric coordinates are the weights of each of the three points, in the order they were
+// given. Returns false if the triangle couldn't be drawn because it has no area.
+pub fn rasterise_triangle<F>(points: [(f64, f64); 3], bounds: Rect, mut f: F) -> bool
+where
+    F: FnMut(usize, usize, [f64; 3]),
+{
+    if points.iter().any(|p| !(p.0.abs() < MAX_COORD && p.1.abs() < MAX_COORD)) {
+        return false;
+    }
+
+    let mut v: Vec<(i64, i64)> = points
+        .iter()
+        .map(|p| ((p.0 * SUB as f64).round() as i64, (p.1 * SUB as f64).round() as i64))
+        .collect();
+
+    // Which of the original points each of v is, since they might get swapped around.
+    let mut order = [0, 1, 2];
+    let mut area = edge_function(v[0], v[1], v[2]);
+
+    if area == 0 {
+        return false;
+    }
+
+    // Wind everything the same way so that inside is always positive.
+    if area < 0 {
+        v.swap(1, 2);
+        order.swap(1, 2);
+        area = -area;
+    }
+
+    // Edge i is opposite vertex i, so its value is vertex i's (unnormalised) weight.
+    let edges = [Edge::from(v[1], v[2]), Edge::from(v[2], v[0]), Edge::from(v[0], v[1])];
+
+    // Bounding box of pixel centres that could be inside, clamped to bounds.
+    let min_x = v.iter().map(|p| p.0).min().unwrap();
+    let min_y = v.iter().map(|p| p.1).min().unwrap();
+    let max_x = v.iter().map(|p| p.0).max().unwrap();
+    let max_y = v.iter().map(|p| p.1).max().unwrap();
+
+    let x0 = clamp_pixel((min_x + SUB - 1).div_euclid(SUB), bounds.0, bounds.2);
+    let y0 = clamp_pixel((min_y + SUB - 1).div_euclid(SUB), bounds.1, bounds.3);
+    let x1 = clamp_pixel(max_x.div_euclid(SUB) + 1, bounds.0, bounds.2);
+    let y1 = clamp_pixel(max_y.div_euclid(SUB) + 1, bounds.1, bounds.3);
+
+    let area = area as f64;
+    let emit = |x: usize, y: usize, w: [i64; 3], f: &mut F| {
+        let mut bary = [0f64; 3];
+
+        for i in 0..3 {
+            bary[order[i]] = w[i] as f64 / area;
+        }
+
+        f(x, y, bary);
+    };
+
+    // Tiles are lined up with the screen rather than the triangle.
+    let mut ty = y0 - y0 % TILE_SIZE;
+
+    while ty < y1 {
+        let mut tx = x0 - x0 % TILE_SIZE;
+
+        while tx < x1 {
+            let (tx0, ty0) = (tx.max(x0), ty.max(y0));
+            let (tx1, ty1) = ((tx + TILE_SIZE).min(x1), (ty + TILE_SIZE).min(y1));
+
+            // The edge functions are linear, so checking the corners of the tile tells us whether
+            // it's completely outside an edge or completely inside all of them.
+            let corners = [(tx0, ty0), (tx1 - 1, ty0), (tx0, ty1 - 1), (tx1 - 1, ty1 - 1)];
+            let mut outside = false;
+            let mut inside = true;
+
+            for e in edges.iter() {
+                let values = corners.map(|c| e.at(c.0, c.1) + e.bias);
+
+                if values.iter().all(|w| *w < 0) {
+                    outside = true;
+                }
+
+                if values.iter().any(|w| *w < 0) {
+                    inside = false;
+                }
+            }
+
+            if !outside {
+                for y in ty0..ty1 {
+                    let mut w = [edges[0].at(tx0, y), edges[1].at(tx0, y), edges[2].at(tx0, y)];
+
+                    for x in tx0..tx1 {
+                        if inside || (0..3).all(|i| w[i] + edges[i].bias >= 0) {
+                            emit(x, y, w, &mut f);
+                        }
+
+                        for i in 0..3 {
+                            w[i] += edges[i].step_x;
+                        }
+                    }
+                }
+            }
+
+            tx += TILE_SIZE;
+        }
+
+        ty += TILE_SIZE;
+    }
+
+    true
+}
+
+fn clamp_pixel(v: i64, min: usize, max: usize) -> usize {
+    v.clamp(min as i64, max as i64) as usize
+}
+
+// Binning tiles for parallel rendering. These are much bigger than TILE_SIZE since each one is a
+// separate job for a thread. Cells are tall so the tiles are wider than they are high.
+pub const BIN_WIDTH: usize = 32;
+pub const BIN_HEIGHT: usize = 16;
+
+// A triangle that's been transformed and is ready to be drawn: screen space points, the depth at
+// each of them and a colour. id goes into the ID buffer wherever the triangle ends up on top, 0
+// meaning nothing.
+#[derive(Debug, Clone)]
+pub struct ScreenTriangle {
+    pub points: [(f64, f64); 3],
+    pub depths: [f64; 3],
+    pub colour: Colour,
+    pub id: u32,
+}
+
+// Splits the screen into bins and works out which triangles touch each one. Triangles are listed
+// in the order they were given, so drawing each bin's list in order gives exactly the same result
+// as drawing all of them in order. Bins with nothing in them are left out.
+pub fn bin_triangles(triangles: &[ScreenTriangle], viewport: (usize, usize)) -> Vec<(Rect, Vec<usize>)> {
+    let (width, height) = viewport;
+    let columns = width.div_ceil(BIN_WIDTH);
+    let rows = height.div_ceil(BIN_HEIGHT);
+    let mut bins: Vec<Vec<usize>> = vec![Vec::new(); columns * rows];
+
+    for (i, t) in triangles.iter().enumerate() {
+        // Antialiased edges can spill a pixel or so outside the triangle itself.
+        let min = t.points.iter().fold((f64::INFINITY, f64::INFINITY), |m, p| (m.0.min(p.0), m.1.min(p.1)));
+        let max = t.points.iter().fold((f64::NEG_INFINITY, f64::NEG_INFINITY), |m, p| (m.0.max(p.0), m.1.max(p.1)));
+
+        if !(min.0.is_finite() && min.1.is_finite() && max.0.is_finite() && max.1.is_finite()) {
+            continue;
+        }
+
+        let x0 = clamp_pixel(min.0.floor() as i64 - 2, 0, width) / BIN_WIDTH;
+        let y0 = clamp_pixel(min.1.floor() as i64 - 2, 0, height) / BIN_HEIGHT;
+        let x1 = clamp_pixel(max.0.ceil() as i64 + 2, 0, width.saturating_sub(1)) / BIN_WIDTH;
+        let y1 = clamp_pixel(max.1.ceil() as i64 + 2, 0, height.saturating_sub(1)) / BIN_HEIGHT;
+
+        for row in y0..(y1 + 1).min(rows) {
+            for column in x0..(x1 + 1).min(columns) {
+                bins[row * columns + column].push(i);
+            }
+        }
+    }
+
+    bins.into_iter()
+        .enumerate()
+        .filter(|(_, b)| !b.is_empty())
+        .map(|(i, b)| {
+            let (column, row) = (i % columns, i / columns);
+            let rect = (
+                column * BIN_WIDTH,
+                row * BIN_HEIGHT,
+                ((column + 1) * BIN_WIDTH).min(width),
+                ((row + 1) * BIN_HEIGHT).min(height),
+            );
+
+            (rect, b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // How many times each pixel of a 32 by 32 screen is written by drawing all of triangles.
+    fn counts(triangles: &[[(f64, f64); 3]]) -> Vec<usize> {
+        let mut counts = vec![0; 32 * 32];
+
+        for t in triangles {
+            rasterise_triangle(*t, (0, 0, 32, 32), |x, y, _| counts[y * 32 + x] += 1);
+        }
+
+        counts
+    }
+
+    #[test]
+    fn shared_edges_drawn_once() {
+        let (a, b, c, d, centre) = ((0.0, 0.0), (16.0, 0.0), (16.0, 16.0), (0.0, 16.0), (8.0, 8.0));
+
+        // A square cut in two along a diagonal, and into four round its centre. Either way the
+        // diagonals run straight through pixel centres.
+        let halves = [[a, b, c], [a, c, d]];
+        let quarters = [[a, b, centre], [b, c, centre], [c, d, centre], [d, a, centre]];
+
+        for triangles in [&halves[..], &quarters[..]] {
+            let counts = counts(triangles);
+
+            // The top and left sides of the square are in it and the bottom and right aren't, so
+            // that's exactly 16 by 16 pixels, each drawn by just one triangle.
+            for y in 0..32 {
+                for x in 0..32 {
+                    let expected = if x < 16 && y < 16 { 1 } else { 0 };
+
+                    assert_eq!(counts[y * 32 + x], expected, "({}, {}) in {:?}", x, y, triangles);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_triangles_draw_nothing() {
+        let degenerate = [
+            [(1.0, 1.0), (1.0, 1.0), (1.0, 1.0)],
+            [(1.0, 1.0), (1.0, 1.0), (10.0, 4.0)],
+            [(0.0, 0.0), (5.0, 5.0), (10.0, 10.0)],
+            [(2.0, 3.0), (2.0, 20.0), (2.0, 7.5)],
+            // Not quite in a line, but close enough that snapping to the sub-pixel grid puts it on
+            // one.
+            [(0.0, 0.0), (10.0, 0.0), (20.0, 0.001)],
+        ];
+
+        for t in degenerate {
+            let mut written = 0;
+
+            assert!(!rasterise_triangle(t, (0, 0, 32, 32), |_, _, _| written += 1), "{:?}", t);
+            assert_eq!(written, 0, "{:?}", t);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// Rays and the things they can hit. Used for picking things on the screen and anything else that
+// needs to ask "what's over there?".
+
+use crate::bounds::*;
+use crate::structures::*;
+
+// Triangles closer to edge-on than this are treated as missed.
+const EPSILON: f64 = 1e-9;
+
+// Everything along origin + t * direction for t >= 0. The direction doesn't have to be normalised,
+// which means a ray can be moved into a mesh's own coordinates and the t of anything it hits there
+// is still right in the world.
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vector,
+    pub direction: Vector,
+}
+
+// Where a ray hit a triangle. t is how far along the ray, and barycentric is the weight of each of
+// the triangle's three vertices at that point.
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub t: f64,
+    pub face: usize,
+    pub barycentric: [f64; 3],
+}
+
+impl Ray {
+    pub fn new(origin: Vector, direction: Vector) -> Ray {
+        Ray {
+            origin: Vector::point(origin.x, origin.y, origin.z),
+            direction: Vector::direction(direction.x, direction.y, direction.z),
+        }
+    }
+
+    pub fn at(&self, t: f64) -> Vector {
+        self.origin + self.direction * t
+    }
+
+    pub fn transformed(&self, m: &Matrix) -> Ray {
+        Ray {
+            origin: m * self.origin,
+            direction: m * self.direction,
+        }
+    }
+
+    // Slab test: where the ray goes into the box, if it does so before max_t. A ray starting
+    // inside the box hits it at 0.
+    pub fn intersect_aabb(&self, aabb: &Aabb, max_t: f64) -> Option<f64> {
+        let mut near = 0.0f64;
+        let mut far = max_t;
+
+        for axis in 0..3 {
+            let inverse = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            // max and min ignore NaN, which is what comes out of a ray lying exactly in the
+            // plane of one of the sides.
+            near = near.max(t0);
+            far = far.min(t1);
+
+            if near > far {
+                return None;
+            }
+        }
+
+        Some(near)
+    }
+
+    // Moller-Trumbore ray/triangle intersection. Gives back (t, barycentric) for a hit from
+    // either side of the triangle.
+    pub fn intersect_triangle(&self, a: &Vector, b: &Vector, c: &Vector) -> Option<(f64, [f64; 3])> {
+        self.moller_trumbore(a, b, c, true)
+    }
+
+    // Where the ray goes through the plane the triangle is in, which might not be inside the
+    // triangle. The barycentric coordinates are outside 0 to 1 if it isn't.
+    pub fn intersect_plane(&self, a: &Vector, b: &Vector, c: &Vector) -> Option<(f64, [f64; 3])> {
+        self.moller_trumbore(a, b, c, false)
+    }
+
+    fn moller_trumbore(&self, a: &Vector, b: &Vector, c: &Vector, inside: bool) -> Option<(f64, [f64; 3])> {
+        let e1 = Vector::direction(b.x - a.x, b.y - a.y, b.z - a.z);
+        let e2 = Vector::direction(c.x - a.x, c.y - a.y, c.z - a.z);
+
+        let p = Vector::cross_product(&self.direction, &e2);
+        let det = Vector::dot_product(&e1, &p);
+
+        if det.abs() < EPSILON {
+            return None;
+        }
+
+        let inverse = 1.0 / det;
+        let s = Vector::direction(self.origin.x - a.x, self.origin.y - a.y, self.origin.z - a.z);
+        let u = Vector::dot_product(&s, &p) * inverse;
+
+        if inside && !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = Vector::cross_product(&s, &e1);
+        let v = Vector::dot_product(&self.direction, &q) * inverse;
+
+        if inside && (v < 0.0 || u + v > 1.0) {
+            return None;
+        }
+
+        let t = Vector::dot_product(&e2, &q) * inverse;
+
+        if t > EPSILON {
+            Some((t, [1.0 - u - v, u, v]))
+        }
+
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Facing +z, wound anticlockwise when looked at from there.
+    fn triangle() -> (Vector, Vector, Vector) {
+        (Vector::point(0.0, 0.0, 0.0), Vector::point(1.0, 0.0, 0.0), Vector::point(0.0, 1.0, 0.0))
+    }
+
+    fn hit(origin: (f64, f64, f64), direction: (f64, f64, f64)) -> Option<(f64, [f64; 3])> {
+        let (a, b, c) = triangle();
+        let ray = Ray::new(Vector::point(origin.0, origin.1, origin.2), Vector::direction(direction.0, direction.1, direction.2));
+
+        ray.intersect_triangle(&a, &b, &c)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn hits_inside() {
+        let (t, b) = hit((0.25, 0.25, 2.0), (0.0, 0.0, -1.0)).unwrap();
+
+        assert!(close(t, 2.0));
+        assert!(close(b[0], 0.5) && close(b[1], 0.25) && close(b[2], 0.25), "{:?}", b);
+
+        // The direction doesn't have to be normalised, and t is in terms of it.
+        let (t, _) = hit((0.25, 0.25, 2.0), (0.0, 0.0, -4.0)).unwrap();
+
+        assert!(close(t, 0.5));
+    }
+
+    #[test]
+    fn misses_outside_and_behind() {
+        assert!(hit((0.6, 0.6, 1.0), (0.0, 0.0, -1.0)).is_none());
+        assert!(hit((-0.1, 0.5, 1.0), (0.0, 0.0, -1.0)).is_none());
+        // Pointing away from the triangle.
+        assert!(hit((0.25, 0.25, 1.0), (0.0, 0.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn grazing_edges() {
+        // Straight through an edge counts as a hit, on the edge.
+        let (t, b) = hit((0.5, 0.0, 1.0), (0.0, 0.0, -1.0)).unwrap();
+
+        assert!(close(t, 1.0));
+        assert!(close(b[0], 0.5) && close(b[1], 0.5) && close(b[2], 0.0), "{:?}", b);
+
+        // Skimming along an edge in the plane of the triangle doesn't.
+        assert!(hit((-1.0, 0.0, 0.0), (1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn hits_back_faces() {
+        let (t, b) = hit((0.25, 0.25, -3.0), (0.0, 0.0, 1.0)).unwrap();
+
+        assert!(close(t, 3.0));
+        assert!(close(b[0], 0.5) && close(b[1], 0.25) && close(b[2], 0.25), "{:?}", b);
+    }
+}
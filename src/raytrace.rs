@@ -0,0 +1,241 @@
+// The other way of drawing things: instead of taking each triangle and working out which cells it
+// covers, fire a ray out of the camera through each cell and see what it hits. Much slower, but it
+// makes shadows and reflections easy, so it's good for stills. Uses the same camera, meshes,
+// lights and framebuffer as the rasteriser so everything after it (present and so on) is the same.
+
+use crate::bvh::*;
+use crate::device::*;
+use crate::lighting::*;
+use crate::pipeline::*;
+use crate::ray::*;
+use crate::render_objects::*;
+use crate::structures::*;
+use crate::target::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+// How far off a surface rays leaving it start, so they don't hit the surface they came from.
+const SURFACE_OFFSET: f64 = 1e-4;
+
+// What was found in a cell: its colour and depth, if the rays hit anything.
+type Cell = Option<(Colour, f64)>;
+
+// Everything the rays need, shared between the threads.
+struct Tracer {
+    bvh: SceneBvh,
+    // The normal matrix of each of bvh's instances
+    normals: Vec<Matrix>,
+    lights: Vec<Light>,
+    ambient: f64,
+    lighting: LightingModel,
+    background: Colour,
+}
+
+impl Tracer {
+    // The colour seen along a ray and how far away it is, or None if it doesn't hit anything.
+    // bounces is how many more times it's allowed to be reflected.
+    fn trace(&self, ray: &Ray, bounces: usize) -> Option<(Colour, f64)> {
+        let hit = self.bvh.intersect_ray(ray, f64::INFINITY)?;
+        let instance = &self.bvh.instances[hit.instance];
+        let face = &instance.mesh.geometry.faces[hit.hit.face];
+        let point = ray.at(hit.hit.t);
+
+        // Faces can be hit from either side, so turn the normal to face back along the ray.
+        let mut normal = transform_normal(&self.normals[hit.instance], face.normal);
+
+        if Vector::dot_product(&normal, &ray.direction) > 0.0 {
+            normal = -normal;
+        }
+
+        let origin = point + normal * SURFACE_OFFSET;
+        let material = instance.mesh.face_material(face);
+
+        let illumination = Illumination {
+            model: self.lighting,
+            lights: &self.lights,
+            ambient: self.ambient,
+            eye: ray.origin,
+        };
+
+        // Hard shadows: a light only counts if nothing's in the way.
+        let mut colour = shade(&illumination, &material, &point, &normal, |i| {
+            let (to_light, distance) = light_direction(&self.lights[i], &point);
+
+            if self.bvh.intersect_ray(&Ray::new(origin, to_light), distance).is_some() { 0.0 } else { 1.0 }
+        });
+        let reflectivity = material.reflectivity;
+
+        if bounces > 0 && reflectivity > 0.0 {
+            let reflected = Ray::new(origin, ray.direction.reflect(&normal));
+            let seen = self.trace(&reflected, bounces - 1).map_or(self.background.clone(), |(c, _)| c);
+
+            colour = seen.over(&colour, reflectivity);
+        }
+
+        Some((colour, hit.hit.t))
+    }
+}
+
+impl Device {
+    // Ray trace the whole frame. Each cell gets ray_samples x ray_samples rays spread evenly over
+    // it, which are averaged, and rays are reflected up to ray_depth times.
+    pub fn ray_trace(&mut self) {
+        let start = Instant::now();
+        let (width, height) = self.dimensions;
+
+        self.scene.update();
+
+        let mut lights = self.lights.clone();
+        lights.extend(self.scene.lights());
+
+        let meshes: Vec<(Matrix, &Mesh)> = instances(&self.meshes, &self.scene).into_iter().map(|(_, world, m)| (world, m)).collect();
+        let bvh = SceneBvh::build(&meshes);
+        let normals = bvh.instances.iter().map(|i| normal_matrix(&(i.world * i.mesh.model_matrix()))).collect();
+
+        let tracer = Tracer {
+            bvh,
+            normals,
+            lights,
+            ambient: self.ambient,
+            lighting: self.lighting,
+            background: self.background.clone(),
+        };
+
+        let aspect = self.aspect();
+        let view_projection = self.camera.projection_matrix(aspect) * self.camera.view_matrix();
+        let samples = self.ray_samples.max(1);
+        let camera = &self.camera;
+
+        // The colour and depth of each cell, a row at a time so each row can be its own job.
+        let rows: Vec<Mutex<Vec<Cell>>> = (0..height).map(|_| Mutex::new(Vec::new())).collect();
+        let next = AtomicUsize::new(0);
+
+        self.stats.transform_time += start.elapsed();
+        let start = Instant::now();
+
+        thread::scope(|s| {
+            for _ in 0..self.threads.clamp(1, height.max(1)) {
+                s.spawn(|| loop {
+                    let y = next.fetch_add(1, Ordering::Relaxed);
+
+                    if y >= height {
+                        break;
+                    }
+
+                    let mut row = rows[y].lock().unwrap();
+
+                    // From a position on the screen in cells to the ray through it.
+                    let ray_at = |sx: f64, sy: f64| camera.ray(sx / width as f64 * 2.0 - 1.0, 1.0 - sy / height as f64 * 2.0, aspect);
+
+                    for x in 0..width {
+                        let mut nearest = f64::INFINITY;
+
+                        let colour = Colour::average((0..samples * samples).map(|i| {
+                            // Spread the samples out evenly over the cell.
+                            let sx = x as f64 + ((i % samples) as f64 + 0.5) / samples as f64;
+                            let sy = y as f64 + ((i / samples) as f64 + 0.5) / samples as f64;
+
+                            match tracer.trace(&ray_at(sx, sy), self.ray_depth) {
+                                Some((c, t)) => {
+                                    nearest = nearest.min(t);
+                                    c
+                                },
+                                None => tracer.background.clone(),
+                            }
+                        }));
+
+                        row.push(if nearest.is_finite() {
+                            // Depth in the same terms as the rasteriser, so the two can be mixed.
+                            let point = ray_at(x as f64 + 0.5, y as f64 + 0.5).at(nearest);
+
+                            Some((colour.unwrap(), (view_projection * point).homogenised().z))
+                        }
+
+                        else {
+                            None
+                        });
+                    }
+                });
+            }
+        });
+
+        for (y, row) in rows.into_iter().enumerate() {
+            for (x, cell) in row.into_inner().unwrap().into_iter().enumerate() {
+                if let Some((colour, depth)) = cell {
+                    self.blend_point(x as i32, y as i32, depth, &colour, 1.0);
+                }
+            }
+        }
+
+        self.stats.raster_time += start.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn tracer(meshes: &[Mesh], background: Colour) -> Tracer {
+        let pairs: Vec<(Matrix, &Mesh)> = meshes.iter().map(|m| (Matrix::identity(), m)).collect();
+        let bvh = SceneBvh::build(&pairs);
+        let normals = bvh.instances.iter().map(|i| normal_matrix(&(i.world * i.mesh.model_matrix()))).collect();
+
+        Tracer {
+            bvh,
+            normals,
+            lights: vec![Light::directional(Vector::direction(0.0, -1.0, 0.0), Colour::Grey(1.0))],
+            ambient: 0.1,
+            lighting: LightingModel::Lambert,
+            background,
+        }
+    }
+
+    fn plane(size: f64, height: f64, material: Material) -> Mesh {
+        let mut mesh = Mesh::from(Arc::new(Geometry::plane(String::from("plane"), size)), Vector::point(0.0, height, 0.0), Quaternion::identity());
+
+        mesh.material = Some(material);
+        mesh
+    }
+
+    // Looking at a point on the ground from just above it and off to the side.
+    fn towards(x: f64) -> Ray {
+        let origin = Vector::point(x, 0.5, 3.0);
+
+        Ray::new(origin, Vector::point(x, 0.0, 0.0) - origin)
+    }
+
+    #[test]
+    fn shadowed_points_are_darker() {
+        // The light shines straight down, and a small square above the ground shadows the middle
+        // of it.
+        let meshes = [plane(10.0, 0.0, Material::new(Colour::Grey(1.0))), plane(2.0, 1.0, Material::new(Colour::Grey(1.0)))];
+        let tracer = tracer(&meshes, Colour::Grey(0.0));
+
+        let (shadowed, t) = tracer.trace(&towards(0.0), 0).unwrap();
+        let (lit, _) = tracer.trace(&towards(3.0), 0).unwrap();
+
+        // Both rays are meant to hit the ground, not the square.
+        assert!((t - 1.0).abs() < 1e-9);
+        assert!(shadowed.intensity() < lit.intensity(), "{:?} isn't darker than {:?}", shadowed, lit);
+    }
+
+    #[test]
+    fn no_bounces_means_no_reflections() {
+        // A dark, shiny floor under a bright sky.
+        let mut shiny = Material::new(Colour::Grey(0.2));
+        shiny.reflectivity = 0.8;
+
+        let reflective = tracer(&[plane(10.0, 0.0, shiny)], Colour::Grey(1.0));
+        let matte = tracer(&[plane(10.0, 0.0, Material::new(Colour::Grey(0.2)))], Colour::Grey(1.0));
+
+        let (reflected, _) = reflective.trace(&towards(0.0), 1).unwrap();
+        let (unreflected, _) = reflective.trace(&towards(0.0), 0).unwrap();
+        let (plain, _) = matte.trace(&towards(0.0), 1).unwrap();
+
+        assert!(reflected.intensity() > unreflected.intensity());
+        assert!((unreflected.intensity() - plain.intensity()).abs() < 1e-12);
+    }
+}
@@ -1,10 +1,14 @@
 // The objects that help render and are to be rendered. Built on the data structures as defined in
 // structures.rs.
 
+use crate::bounds::*;
+use crate::bvh::*;
+use crate::ray::*;
 use crate::structures::*;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::ops::Mul;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum Colour {
@@ -12,12 +16,44 @@ pub enum Colour {
     Grey(f64),                // (a)
 }
 
-// The point of view of which to render from
+// The point of view of which to render from. With no rotation the camera looks down -z with +y
+// up, and rot turns it from there.
 #[derive(Debug, Clone)]
 pub struct Camera {
     pub pos: Vector,
-    pub target: Vector,
-    pub up: Vector,
+    pub rot: Quaternion,
+    // Vertical field of view in radians, and the distances to the near and far clipping planes.
+    pub fov: f64,
+    pub near: f64,
+    pub far: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    // Infinitely far away, like the sun. Only the direction matters.
+    Directional,
+    // Shines equally in every direction from pos.
+    Point,
+    // Shines from pos in a cone around direction.
+    Spot,
+}
+
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub kind: LightKind,
+    pub pos: Vector,
+    // The direction the light is travelling in. Not used by point lights.
+    pub direction: Vector,
+    pub colour: Colour,
+    pub intensity: f64,
+    // Half the angle of a spotlight's cone, in radians.
+    pub cone: f64,
+    // Half the angle of the part of a spotlight's cone at full strength, in radians. It fades out
+    // from here to cone.
+    pub inner_cone: f64,
+    // How the light gets dimmer with distance d: its strength is divided by
+    // attenuation[0] + attenuation[1] * d + attenuation[2] * d * d. Not used by directional lights.
+    pub attenuation: [f64; 3],
 }
 
 #[derive(Debug, Clone)]
@@ -25,85 +61,459 @@ pub struct Face {
     pub vertices: [usize; 3],
     pub colour: Colour,
     pub normal: Vector,
+    // Which of the geometry's materials the face uses, if any.
+    pub material: Option<usize>,
 }
 
-// A mesh to be rendered. Contains vertex information and the like.
+// What a surface looks like.
 #[derive(Debug, Clone)]
-pub struct Mesh {
+pub struct Material {
+    pub colour: Colour,
+    // How much of what's around it the surface reflects, from 0 to 1. Only the ray tracer can do
+    // reflections.
+    pub reflectivity: f64,
+    // How much ambient light the surface reflects.
+    pub ambient: f64,
+    // How strong specular highlights are, and how shiny the surface is. The shinier it is, the
+    // smaller and sharper the highlights. Only used by the Blinn-Phong lighting model.
+    pub specular: f64,
+    pub shininess: f64,
+}
+
+// The shape of something: vertex information and the like, in its own coordinates. This never
+// changes once it's loaded and is shared between every mesh drawn with it, so a hundred donuts
+// only need one donut's worth of vertices.
+#[derive(Debug, Clone)]
+pub struct Geometry {
+    #[allow(dead_code)]
     pub name: String,
     pub vertices: Vec<Vector>,
     pub faces: Vec<Face>,
+    // From the .mtl file next to the .obj, if there is one.
+    pub materials: Vec<Material>,
+    // Both of these contain every vertex. The sphere is quicker to test but usually looser.
+    pub bounds: Aabb,
+    pub sphere: BoundingSphere,
+    // Over the faces, for finding what a ray hits without checking all of them.
+    pub bvh: Bvh,
+}
+
+// A mesh to be rendered: some geometry and where to put it. pos, rot and scale are applied when
+// it's drawn. If there's a material it's used instead of the colours of the faces.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub geometry: Arc<Geometry>,
     pub pos: Vector,
-    pub rot: Vector,
+    pub rot: Quaternion,
+    pub scale: Vector,
+    pub material: Option<Material>,
+}
+
+impl Colour {
+    // How bright the colour is overall, from 0 to 1. Rgba colours are treated as if they were
+    // drawn over black.
+    pub fn intensity(&self) -> f64 {
+        match *self {
+            Colour::Rgba(r, g, b, a) => (0.2126*r + 0.7152*g + 0.0722*b) * a,
+            Colour::Grey(a) => a,
+        }
+    }
+
+    // The colour made brighter or darker, e.g. by lighting. Alpha is left alone.
+    pub fn scaled(&self, f: f64) -> Colour {
+        match *self {
+            Colour::Rgba(r, g, b, a) => Colour::Rgba(r*f, g*f, b*f, a),
+            Colour::Grey(a) => Colour::Grey(a*f),
+        }
+    }
+
+    pub fn to_rgba(&self) -> (f64, f64, f64, f64) {
+        match *self {
+            Colour::Rgba(r, g, b, a) => (r, g, b, a),
+            Colour::Grey(a) => (a, a, a, 1.0),
+        }
+    }
+
+    // Composite this colour over dst, with coverage being how much of the pixel is covered (e.g.
+    // by an antialiased line). The result stays in the same colour space as dst.
+    pub fn over(&self, dst: &Colour, coverage: f64) -> Colour {
+        let coverage = coverage.clamp(0.0, 1.0);
+
+        match (self, dst) {
+            (Colour::Grey(s), Colour::Grey(d)) => Colour::Grey(s*coverage + d*(1.0-coverage)),
+
+            (_, Colour::Grey(d)) => {
+                let (_, _, _, a) = self.to_rgba();
+                let alpha = a * coverage;
+
+                Colour::Grey(self.intensity() / a.max(f64::EPSILON) * alpha + d*(1.0-alpha))
+            },
+
+            (_, Colour::Rgba(dr, dg, db, da)) => {
+                let (r, g, b, a) = self.to_rgba();
+                let alpha = a * coverage;
+
+                Colour::Rgba(
+                    r*alpha + dr*(1.0-alpha),
+                    g*alpha + dg*(1.0-alpha),
+                    b*alpha + db*(1.0-alpha),
+                    alpha + da*(1.0-alpha),
+                )
+            },
+        }
+    }
+
+    // The average of some colours (e.g. the samples in a cell), or None if there aren't any. Rgba
+    // colours are averaged premultiplied, so a transparent one adds nothing but still counts towards
+    // how much of the cell is covered, and the order they come in doesn't matter. If they're all
+    // Grey the average is too.
+    pub fn average<I: IntoIterator<Item = Colour>>(colours: I) -> Option<Colour> {
+        let mut n = 0;
+        let mut grey = true;
+        let (mut r, mut g, mut b, mut a) = (0.0, 0.0, 0.0, 0.0);
+
+        for c in colours {
+            let (cr, cg, cb, ca) = c.to_rgba();
+
+            grey &= matches!(c, Colour::Grey(_));
+            r += cr * ca;
+            g += cg * ca;
+            b += cb * ca;
+            a += ca;
+            n += 1;
+        }
+
+        if n == 0 {
+            None
+        }
+
+        else if grey {
+            Some(Colour::Grey(r / n as f64))
+        }
+
+        // Nothing there at all, so there's no colour to un-premultiply.
+        else if a <= 0.0 {
+            Some(Colour::Rgba(0.0, 0.0, 0.0, 0.0))
+        }
+
+        else {
+            Some(Colour::Rgba(r / a, g / a, b / a, a / n as f64))
+        }
+    }
+}
+
+impl Light {
+    pub fn directional(direction: Vector, colour: Colour) -> Light {
+        Light {
+            kind: LightKind::Directional,
+            pos: Vector::new(),
+            direction: Vector::direction(direction.x, direction.y, direction.z).normalised(),
+            colour,
+            intensity: 1.0,
+            cone: 0.0,
+            inner_cone: 0.0,
+            attenuation: [1.0, 0.0, 0.0],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn point(pos: Vector, colour: Colour) -> Light {
+        Light {
+            kind: LightKind::Point,
+            pos,
+            direction: Vector::direction(0.0, -1.0, 0.0),
+            colour,
+            intensity: 1.0,
+            cone: 0.0,
+            inner_cone: 0.0,
+            attenuation: [1.0, 0.0, 0.0],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn spot(pos: Vector, direction: Vector, cone: f64, colour: Colour) -> Light {
+        Light {
+            kind: LightKind::Spot,
+            pos,
+            direction: Vector::direction(direction.x, direction.y, direction.z).normalised(),
+            colour,
+            intensity: 1.0,
+            cone,
+            inner_cone: cone,
+            attenuation: [1.0, 0.0, 0.0],
+        }
+    }
+
+    // The same light moved by a matrix, e.g. into world space from the node it's attached to.
+    pub fn transformed(&self, m: &Matrix) -> Light {
+        let mut light = self.clone();
+
+        light.pos = m * self.pos;
+        light.direction = (m * self.direction).normalised();
+        light
+    }
 }
 
 impl Camera {
     pub fn new() -> Camera {
         Camera {
             pos: Vector::new(),
-            target: Vector::new(),
-            up: Vector::from(0.0, 1.0, 0.0, 0.0),
+            rot: Quaternion::identity(),
+            fov: std::f64::consts::FRAC_PI_3,
+            near: 0.1,
+            far: 100.0,
         }
     }
 
+    // A camera at pos looking at target, with up being roughly upwards on the screen.
     pub fn from(pos: Vector, target: Vector, up: Vector) -> Camera {
+        let mut camera = Camera {
+            pos,
+            ..Camera::new()
+        };
+
+        camera.look_at(target, up);
+        camera
+    }
+
+    pub fn look_at(&mut self, target: Vector, up: Vector) {
+        let forward = Vector::direction(target.x - self.pos.x, target.y - self.pos.y, target.z - self.pos.z).normalised();
+        let right = Vector::cross_product(&forward, &up).normalised();
+        let up = Vector::cross_product(&right, &forward);
+
+        // The columns are where the camera's own x, y and z axes end up.
+        let m = Matrix {
+            vals: [[right.x, up.x, -forward.x, 0.0],
+                   [right.y, up.y, -forward.y, 0.0],
+                   [right.z, up.z, -forward.z, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        };
+
+        self.rot = Quaternion::from_matrix(&m);
+    }
+
+    #[allow(dead_code)]
+    pub fn forward(&self) -> Vector {
+        self.rot.rotate(Vector::direction(0.0, 0.0, -1.0))
+    }
+
+    #[allow(dead_code)]
+    pub fn up(&self) -> Vector {
+        self.rot.rotate(Vector::direction(0.0, 1.0, 0.0))
+    }
+
+    pub fn right(&self) -> Vector {
+        self.rot.rotate(Vector::direction(1.0, 0.0, 0.0))
+    }
+
+    // Swing the camera round a point, keeping it facing the same way relative to it. yaw turns
+    // about the world's up axis and pitch about the camera's right, so there's no gimbal lock
+    // however far round you go.
+    #[allow(dead_code)]
+    pub fn orbit(&mut self, centre: Vector, yaw: f64, pitch: f64) {
+        let turn = Quaternion::from_axis_angle(Vector::direction(0.0, 1.0, 0.0), yaw)
+            * Quaternion::from_axis_angle(self.right(), pitch);
+
+        let offset = Vector::direction(self.pos.x - centre.x, self.pos.y - centre.y, self.pos.z - centre.z);
+        let offset = turn.rotate(offset);
+
+        self.pos = Vector::point(centre.x + offset.x, centre.y + offset.y, centre.z + offset.z);
+        self.rot = (turn * self.rot).normalised();
+    }
+
+    // The same camera moved by a matrix, e.g. into world space from the node it's attached to.
+    // The matrix shouldn't have any scaling in it.
+    #[allow(dead_code)]
+    pub fn transformed(&self, m: &Matrix) -> Camera {
         Camera {
-            pos: pos,
-            target: target,
-            up: up, }
+            pos: m * self.pos,
+            rot: (Quaternion::from_matrix(m) * self.rot).normalised(),
+            ..self.clone()
+        }
+    }
+
+    // The matrix that takes world coordinates to the camera's coordinates.
+    pub fn view_matrix(&self) -> Matrix {
+        self.rot.conjugate().to_matrix() * Matrix::translation(-self.pos)
+    }
+
+    // The perspective projection, for a screen aspect ratio of width / height.
+    pub fn projection_matrix(&self, aspect: f64) -> Matrix {
+        Matrix::perspective(self.fov, aspect, self.near, self.far)
+    }
+
+    // How far in front of the camera something is, from its depth in normalised device
+    // coordinates (what's in the depth buffer). The opposite of what the projection does to z.
+    pub fn linear_depth(&self, ndc_z: f64) -> f64 {
+        2.0 * self.far * self.near / ((self.far + self.near) - ndc_z * (self.far - self.near))
+    }
+
+    // The ray from the camera through a point on the screen, given in normalised device
+    // coordinates: -1 to 1 across and up, the same as the projection matrix gives. Starts at the
+    // camera rather than the near plane, and the direction is normalised so t is the distance.
+    pub fn ray(&self, x: f64, y: f64, aspect: f64) -> Ray {
+        let tha = (self.fov / 2.0).tan();
+        let direction = Vector::direction(x * tha * aspect, y * tha, -1.0);
+
+        Ray::new(self.pos, self.rot.rotate(direction).normalised())
     }
 }
 
-impl Mesh {
-    pub fn new(name: String) -> Mesh {
-        Mesh {
-            name: name,
-            vertices: Vec::new(),
-            faces: Vec::new(),
-            pos: Vector::new(),
-            rot: Vector::new(),
+impl Material {
+    pub fn new(colour: Colour) -> Material {
+        Material {
+            colour,
+            reflectivity: 0.0,
+            ambient: 1.0,
+            specular: 0.0,
+            shininess: 32.0,
         }
     }
 
-    pub fn from(name: String, vertices: Vec<Vector>, faces: Vec<Face>, pos: Vector, rot: Vector) -> Mesh {
-        // This function is pretty much useless because actually implementing it would be horrific.
-        // Instead, use from_file.
-        Mesh {
-            name: name,
-            vertices: vertices,
-            faces: faces,
-            pos: pos,
-            rot: rot,
+    // Reads the materials in a .mtl file, with their names. Colours are made grey if all three
+    // channels are the same, like the rest of the colours in here.
+    pub fn from_file(filename: &Path) -> Result<Vec<(String, Material)>, String> {
+        let contents = fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename.display(), e))?;
+        let mut materials: Vec<(String, Material)> = Vec::new();
+
+        // Three numbers in a row, from the line l, e.g. Kd 0.64 0.64 0.64.
+        let triple = |l: &[&str]| -> (f64, f64, f64) {
+            let n: Vec<f64> = l[1..].iter().filter_map(|s| s.parse::<f64>().ok()).collect();
+
+            match n.len() {
+                0 => (0.0, 0.0, 0.0),
+                1 | 2 => (n[0], n[0], n[0]),
+                _ => (n[0], n[1], n[2]),
+            }
+        };
+
+        for line in contents.lines() {
+            let l: Vec<&str> = line.split_whitespace().collect();
+
+            if l.is_empty() {
+                continue;
+            }
+
+            if l[0] == "newmtl" {
+                materials.push((l[1..].join(" "), Material::new(Colour::Grey(1.0))));
+                continue;
+            }
+
+            let material = match materials.last_mut() {
+                Some((_, m)) => m,
+                None => continue,
+            };
+
+            match l[0] {
+                "Kd" => {
+                    let (r, g, b) = triple(&l);
+
+                    material.colour = if r == g && g == b { Colour::Grey(r) } else { Colour::Rgba(r, g, b, 1.0) };
+                },
+
+                "Ka" => {
+                    let (r, g, b) = triple(&l);
+
+                    material.ambient = (r + g + b) / 3.0;
+                },
+
+                "Ks" => {
+                    let (r, g, b) = triple(&l);
+
+                    material.specular = (r + g + b) / 3.0;
+                },
+
+                // Blender writes 0 to 1000. Anything under 1 would light up the whole side of a
+                // surface facing the light.
+                "Ns" => {
+                    material.shininess = l.get(1).and_then(|s| s.parse::<f64>().ok()).unwrap_or(32.0).max(1.0);
+                },
+
+                _ => {}
+            }
         }
+
+        Ok(materials)
+    }
+}
+
+impl Geometry {
+    // Geometry from vertices and faces made in code, like plane does, rather than read from a file.
+    // Works out the bounding box and sphere and builds an SAH BVH over the faces. There are no
+    // materials, so the faces are drawn in their own colours unless the mesh has one.
+    pub fn from(name: String, vertices: Vec<Vector>, faces: Vec<Face>) -> Geometry {
+        let mut geometry = Geometry {
+            name,
+            bounds: Aabb::from_points(&vertices),
+            sphere: BoundingSphere::from_points(&vertices),
+            bvh: Bvh::new(),
+            vertices,
+            faces,
+            materials: Vec::new(),
+        };
+
+        geometry.bvh = Bvh::build(&geometry.face_bounds(), SplitMethod::Sah);
+        geometry
     }
 
-    // Reads a file containing vector information and returns a mesh.
+    // A flat square on the ground (y = 0), size across, facing up.
+    pub fn plane(name: String, size: f64) -> Geometry {
+        let s = size / 2.0;
+        let vertices = vec![
+            Vector::point(-s, 0.0, -s),
+            Vector::point(s, 0.0, -s),
+            Vector::point(s, 0.0, s),
+            Vector::point(-s, 0.0, s),
+        ];
+
+        let up = Vector::direction(0.0, 1.0, 0.0);
+        let faces = vec![
+            Face::from(0, 3, 2, up, Colour::Grey(1.0)),
+            Face::from(0, 2, 1, up, Colour::Grey(1.0)),
+        ];
+
+        Geometry::from(name, vertices, faces)
+    }
+
+    // Reads a file containing vector information and returns the geometry in it.
     // Much easier than just making a vector with the information like *some people I know*
-    pub fn from_file(filename: String, pos: Vector, rot: Vector) -> Result<Mesh, String> {
-        let mut f: File = File::open(filename).unwrap();
+    pub fn from_file(filename: String) -> Result<Geometry, String> {
+        let mut f: File = File::open(&filename).unwrap();
         let mut contents = String::new();
 
         f.read_to_string(&mut contents).unwrap();
         
-        let mut lines: Vec<&str> = contents.split("\n").collect();
+        let lines: Vec<&str> = contents.split("\n").collect();
         let mut vertex_data: Vec<Vector> = Vec::new();
-        let mut faces: Vec<([usize; 3], usize)> = Vec::new();
+        let mut faces: Vec<([usize; 3], usize, Option<usize>)> = Vec::new();
         let mut normals: Vec<Vector> = Vec::new();
+        let mut materials: Vec<(String, Material)> = Vec::new();
+        let mut material: Option<usize> = None;
 
-        let mut mesh = Mesh {
-            name: String::new(),
-            vertices: Vec::new(),
-            faces: Vec::new(),
-            pos: pos,
-            rot: rot,
-        };
+        let mut name = String::new();
 
-        for i in 0..(lines.len()-1) { // Note that the last element of lines is an empty list.  
-            let mut l: Vec<&str> = lines[i].split(" ").collect(); 
+        for line in &lines[..lines.len()-1] { // Note that the last element of lines is an empty list.  
+            let l: Vec<&str> = line.split(" ").collect(); 
 
             match l[0] {
                 "o" => {
-                    mesh.name = String::from(l[1]);
+                    name = String::from(l[1]);
+                },
+
+                // Material files are looked for next to the .obj. It's fine if one's missing, the
+                // faces are just white.
+                "mtllib" => {
+                    let path = Path::new(&filename).with_file_name(l[1..].join(" "));
+
+                    if let Ok(m) = Material::from_file(&path) {
+                        materials.extend(m);
+                    }
+                },
+
+                "usemtl" => {
+                    let wanted = l[1..].join(" ");
+
+                    material = materials.iter().position(|(name, _)| *name == wanted);
                 },
 
                 "v" => {
@@ -142,7 +552,7 @@ impl Mesh {
                         vertices[j-1] = vf[0].parse::<usize>().unwrap()-1;
                     }
 
-                    faces.push((vertices, normal));
+                    faces.push((vertices, normal, material));
                 },
 
                 _ => {}
@@ -152,31 +562,72 @@ impl Mesh {
         let mut face_structs: Vec<Face> = Vec::new();
 
         for i in 0..faces.len() {
-            let test = normals[faces[i].1].clone();
+            let colour = faces[i].2.map_or(Colour::Grey(1.0), |m| materials[m].1.colour.clone());
+            let mut face = Face::from(faces[i].0[0], faces[i].0[1], faces[i].0[2], normals[faces[i].1], colour);
 
-            face_structs.push(
-                Face::from(faces[i].0[0], faces[i].0[1], faces[i].0[2], normals[faces[i].1].clone(), Colour::Grey(1.0)) // TODO Actually calculate colour
-            );
+            face.material = faces[i].2;
+            face_structs.push(face);
         }
 
-        mesh.vertices = vertex_data;
-        mesh.faces = face_structs;
+        let mut geometry = Geometry::from(name, vertex_data, face_structs);
 
-        Ok(mesh) 
+        geometry.materials = materials.into_iter().map(|(_, m)| m).collect();
+        Ok(geometry)
     }
+
 }
 
-impl Mul<Matrix> for Mesh {
-    type Output = Mesh;
+impl Mesh {
+    pub fn new(geometry: Arc<Geometry>) -> Mesh {
+        Mesh::from(geometry, Vector::new(), Quaternion::identity())
+    }
 
-    fn mul(self, rhs: Matrix) -> Mesh {
-        let mut m = self.clone();
-        
-        for i in 0..m.vertices.len() {
-            m.vertices[i] = rhs.clone()*m.vertices[i].clone();
+    pub fn from(geometry: Arc<Geometry>, pos: Vector, rot: Quaternion) -> Mesh {
+        Mesh {
+            geometry,
+            pos,
+            rot,
+            scale: Vector::direction(1.0, 1.0, 1.0),
+            material: None,
+        }
+    }
+
+    // Loads the geometry and puts a single mesh of it at pos. For more than one of the same thing,
+    // load the Geometry once and make each mesh from it instead.
+    #[allow(dead_code)]
+    pub fn from_file(filename: String, pos: Vector, rot: Quaternion) -> Result<Mesh, String> {
+        Ok(Mesh::from(Arc::new(Geometry::from_file(filename)?), pos, rot))
+    }
+
+    // Another mesh sharing the same geometry, somewhere else.
+    #[allow(dead_code)]
+    pub fn instance(&self, pos: Vector, rot: Quaternion) -> Mesh {
+        Mesh {
+            pos,
+            rot,
+            ..self.clone()
         }
+    }
+
+    // The mesh's bounding box and sphere once it's been moved into place by model, which should
+    // include model_matrix.
+    pub fn bounds(&self, model: &Matrix) -> (Aabb, BoundingSphere) {
+        (self.geometry.bounds.transformed(model), self.geometry.sphere.transformed(model))
+    }
+
+    // What a face looks like: the mesh's material if it has one, otherwise the one the face was
+    // given in its file, otherwise just its colour.
+    pub fn face_material(&self, face: &Face) -> Material {
+        match (&self.material, face.material) {
+            (Some(material), _) => material.clone(),
+            (None, Some(m)) => self.geometry.materials[m].clone(),
+            (None, None) => Material::new(face.colour.clone()),
+        }
+    }
 
-        m
+    // The model matrix: takes the mesh's own coordinates to world coordinates.
+    pub fn model_matrix(&self) -> Matrix {
+        Matrix::translation(self.pos) * self.rot.to_matrix() * Matrix::scale(self.scale)
     }
 }
 
@@ -184,16 +635,53 @@ impl Face {
     fn from(v1: usize, v2: usize, v3: usize, normal: Vector, colour: Colour) -> Face {
         Face {
             vertices: [v1, v2, v3],
-            colour: colour,
-            normal: normal,
+            colour,
+            normal,
+            material: None,
         }
     } 
+}
 
-    fn new() -> Face {
-        Face {
-            vertices: [0; 3],
-            colour: Colour::Grey(1.0),
-            normal: Vector::new(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Colour, b: &Colour) -> bool {
+        let (a, b) = (a.to_rgba(), b.to_rgba());
+
+        (a.0 - b.0).abs() < 1e-12 && (a.1 - b.1).abs() < 1e-12 && (a.2 - b.2).abs() < 1e-12 && (a.3 - b.3).abs() < 1e-12
+    }
+
+    #[test]
+    fn average_ignores_order() {
+        let red = Colour::Rgba(1.0, 0.0, 0.0, 1.0);
+        let clear = Colour::Rgba(0.0, 0.0, 0.0, 0.0);
+        let blue = Colour::Rgba(0.0, 0.2, 1.0, 0.5);
+
+        let average = |colours: &[&Colour]| Colour::average(colours.iter().map(|c| (*c).clone())).unwrap();
+
+        assert!(close(&average(&[&red, &clear]), &Colour::Rgba(1.0, 0.0, 0.0, 0.5)));
+        assert!(close(&average(&[&clear, &red]), &Colour::Rgba(1.0, 0.0, 0.0, 0.5)));
+        assert!(close(&average(&[&red, &clear, &clear, &clear]), &Colour::Rgba(1.0, 0.0, 0.0, 0.25)));
+        assert!(close(&average(&[&clear, &clear]), &clear));
+
+        // Every order of three different colours comes out the same.
+        let colours = [&red, &clear, &blue];
+        let expected = average(&colours);
+        let orders = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+
+        for order in orders {
+            let c = average(&order.map(|i| colours[i]));
+
+            assert!(close(&c, &expected), "{:?}: {:?} vs {:?}", order, c, expected);
         }
     }
+
+    #[test]
+    fn average_of_greys_is_grey() {
+        let c = Colour::average([Colour::Grey(0.2), Colour::Grey(0.4), Colour::Grey(0.9)]).unwrap();
+
+        assert!(matches!(c, Colour::Grey(v) if (v - 0.5).abs() < 1e-12), "{:?}", c);
+        assert!(Colour::average(Vec::new()).is_none());
+    }
 }
@@ -0,0 +1,203 @@
+// The scene graph. Every node has a transform relative to its parent, so moving a node moves
+// everything underneath it too. Meshes, cameras and lights hang off nodes, which means an arm can
+// be swung about at the shoulder without touching any of the vertices in it.
+
+use crate::render_objects::*;
+use crate::structures::*;
+
+// Position, rotation and scale. Applied scale first, then rotation, then translation.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub translation: Vector,
+    pub rotation: Quaternion,
+    pub scale: Vector,
+}
+
+impl Transform {
+    pub fn new() -> Transform {
+        Transform {
+            translation: Vector::direction(0.0, 0.0, 0.0),
+            rotation: Quaternion::identity(),
+            scale: Vector::direction(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn from(translation: Vector, rotation: Quaternion, scale: Vector) -> Transform {
+        Transform {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix {
+        Matrix::translation(self.translation) * self.rotation.to_matrix() * Matrix::scale(self.scale)
+    }
+}
+
+// Index of a node in its scene.
+pub type NodeId = usize;
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    pub mesh: Option<Mesh>,
+    pub camera: Option<Camera>,
+    pub light: Option<Light>,
+
+    // Private so that changing them has to go through the scene, which keeps track of what needs
+    // recalculating.
+    transform: Transform,
+    world: Matrix,
+    dirty: bool,
+}
+
+impl Node {
+    #[allow(dead_code)]
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scene {
+    pub nodes: Vec<Node>,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene::default()
+    }
+
+    // Adds an empty node and returns its id. parent being None makes it a root node.
+    pub fn add_node(&mut self, name: &str, parent: Option<NodeId>, transform: Transform) -> NodeId {
+        let id = self.nodes.len();
+
+        self.nodes.push(Node {
+            name: String::from(name),
+            parent,
+            children: Vec::new(),
+            mesh: None,
+            camera: None,
+            light: None,
+            transform,
+            world: Matrix::identity(),
+            dirty: true,
+        });
+
+        if let Some(p) = parent {
+            self.nodes[p].children.push(id);
+        }
+
+        id
+    }
+
+    #[allow(dead_code)]
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|n| n.name == name)
+    }
+
+    #[allow(dead_code)]
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) {
+        self.nodes[id].transform = transform;
+        self.mark_dirty(id);
+    }
+
+    // Change a node's transform in place, e.g. scene.modify(arm, |t| t.rotation = ...)
+    pub fn modify<F: FnOnce(&mut Transform)>(&mut self, id: NodeId, f: F) {
+        f(&mut self.nodes[id].transform);
+        self.mark_dirty(id);
+    }
+
+    // Move a node (and everything under it) to a different parent.
+    #[allow(dead_code)]
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        // Don't let a node end up underneath itself.
+        let mut p = parent;
+
+        while let Some(i) = p {
+            if i == id {
+                return;
+            }
+
+            p = self.nodes[i].parent;
+        }
+
+        if let Some(old) = self.nodes[id].parent {
+            self.nodes[old].children.retain(|c| *c != id);
+        }
+
+        if let Some(new) = parent {
+            self.nodes[new].children.push(id);
+        }
+
+        self.nodes[id].parent = parent;
+        self.mark_dirty(id);
+    }
+
+    // A node's world matrix depends on all of its parents, so when one changes all of its
+    // descendants need redoing too.
+    fn mark_dirty(&mut self, id: NodeId) {
+        let mut stack = vec![id];
+
+        while let Some(i) = stack.pop() {
+            // Already dirty means everything below it is as well.
+            if self.nodes[i].dirty && i != id {
+                continue;
+            }
+
+            self.nodes[i].dirty = true;
+            stack.extend(self.nodes[i].children.iter());
+        }
+    }
+
+    // The matrix taking a node's local coordinates to world coordinates. Only recalculated if
+    // something it depends on has changed since last time.
+    pub fn world_matrix(&mut self, id: NodeId) -> Matrix {
+        if self.nodes[id].dirty {
+            let local = self.nodes[id].transform.matrix();
+
+            self.nodes[id].world = match self.nodes[id].parent {
+                Some(p) => self.world_matrix(p) * local,
+                None => local,
+            };
+
+            self.nodes[id].dirty = false;
+        }
+
+        self.nodes[id].world
+    }
+
+    // Bring every world matrix up to date.
+    pub fn update(&mut self) {
+        for id in 0..self.nodes.len() {
+            self.world_matrix(id);
+        }
+    }
+
+    // Every mesh in the scene along with which node it's on and its world matrix. Call update
+    // first.
+    pub fn mesh_nodes(&self) -> Vec<(NodeId, Matrix, &Mesh)> {
+        self.nodes.iter()
+            .enumerate()
+            .filter_map(|(id, n)| n.mesh.as_ref().map(|m| (id, n.world, m)))
+            .collect()
+    }
+
+    // Every light in the scene, moved into world space. Call update first.
+    pub fn lights(&self) -> Vec<Light> {
+        self.nodes.iter()
+            .filter_map(|n| n.light.as_ref().map(|l| l.transformed(&n.world)))
+            .collect()
+    }
+
+    // Every camera in the scene, moved into world space. Call update first.
+    #[allow(dead_code)]
+    pub fn cameras(&self) -> Vec<Camera> {
+        self.nodes.iter()
+            .filter_map(|n| n.camera.as_ref().map(|c| c.transformed(&n.world)))
+            .collect()
+    }
+}
@@ -0,0 +1,436 @@
+// Programmable shading. Instead of the fixed flat shading rasterise does, meshes can be drawn with
+// a vertex shader, which places each corner of a face in clip space and hands on whatever it likes
+// (the varyings), and a fragment shader, which is given those varyings interpolated across the face
+// at each cell and picks its colour. Both also get the uniforms for the draw call: the usual
+// matrices and anything else the caller wants to pass in.
+
+use crate::antialias::*;
+use crate::bounds::*;
+use crate::device::*;
+use crate::pipeline::*;
+use crate::render_objects::*;
+use crate::structures::*;
+use crate::target::*;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Anything that can be interpolated across a triangle.
+pub trait Varyings: Clone {
+    // The weighted sum of three lots of varyings, one from each corner. The weights add up to 1.
+    fn interpolate(v: [&Self; 3], weights: [f64; 3]) -> Self;
+}
+
+// The same for every vertex and fragment of one draw call.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Uniforms<'a, U> {
+    // Takes the mesh's own coordinates to world coordinates, including its pos, rot and scale.
+    pub model: Matrix,
+    pub view: Matrix,
+    pub projection: Matrix,
+    pub mvp: Matrix,
+    // For transforming normals into world space (see transform_normal).
+    pub normals: Matrix,
+    // Where the camera is in the world.
+    pub eye: Vector,
+    // Whatever else the shaders need.
+    pub custom: &'a U,
+}
+
+// One corner of a face, as given to a vertex shader.
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct VertexInput<'a> {
+    // In the mesh's own coordinates.
+    pub position: Vector,
+    // The face's normal, also in the mesh's own coordinates. There are no vertex normals, so this
+    // is the same for all three corners.
+    pub normal: Vector,
+    pub face: usize,
+    // Which of the face's three vertices this is.
+    pub corner: usize,
+    pub material: &'a Material,
+}
+
+// A cell being shaded.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct Fragment {
+    pub x: usize,
+    pub y: usize,
+    // Normalised device z, the same as goes in the depth buffer.
+    pub depth: f64,
+}
+
+pub trait VertexShader<U> {
+    type Varyings: Varyings;
+
+    // The position of the vertex in clip space, and the varyings to go with it.
+    fn vertex(&self, uniforms: &Uniforms<U>, input: &VertexInput) -> (Vector, Self::Varyings);
+}
+
+pub trait FragmentShader<U, V> {
+    // The colour of a fragment, or None to leave it out altogether (nothing is drawn and the depth
+    // buffer is left alone).
+    fn fragment(&self, uniforms: &Uniforms<U>, varyings: &V, fragment: &Fragment) -> Option<Colour>;
+}
+
+impl Varyings for () {
+    fn interpolate(_: [&(); 3], _: [f64; 3]) {}
+}
+
+impl Varyings for f64 {
+    fn interpolate(v: [&f64; 3], w: [f64; 3]) -> f64 {
+        v[0] * w[0] + v[1] * w[1] + v[2] * w[2]
+    }
+}
+
+impl<const N: usize> Varyings for [f64; N] {
+    fn interpolate(v: [&[f64; N]; 3], w: [f64; 3]) -> [f64; N] {
+        let mut out = [0.0; N];
+
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = v[0][i] * w[0] + v[1][i] * w[1] + v[2][i] * w[2];
+        }
+
+        out
+    }
+}
+
+impl Varyings for Vector {
+    fn interpolate(v: [&Vector; 3], w: [f64; 3]) -> Vector {
+        weighted(&[*v[0], *v[1], *v[2]], w)
+    }
+}
+
+// Stays grey if all three are.
+impl Varyings for Colour {
+    fn interpolate(v: [&Colour; 3], w: [f64; 3]) -> Colour {
+        if let (Colour::Grey(a), Colour::Grey(b), Colour::Grey(c)) = (v[0], v[1], v[2]) {
+            return Colour::Grey(a * w[0] + b * w[1] + c * w[2]);
+        }
+
+        let (c0, c1, c2) = (v[0].to_rgba(), v[1].to_rgba(), v[2].to_rgba());
+
+        Colour::Rgba(
+            c0.0 * w[0] + c1.0 * w[1] + c2.0 * w[2],
+            c0.1 * w[0] + c1.1 * w[1] + c2.1 * w[2],
+            c0.2 * w[0] + c1.2 * w[1] + c2.2 * w[2],
+            c0.3 * w[0] + c1.3 * w[1] + c2.3 * w[2],
+        )
+    }
+}
+
+impl<A: Varyings, B: Varyings> Varyings for (A, B) {
+    fn interpolate(v: [&(A, B); 3], w: [f64; 3]) -> (A, B) {
+        (
+            A::interpolate([&v[0].0, &v[1].0, &v[2].0], w),
+            B::interpolate([&v[0].1, &v[1].1, &v[2].1], w),
+        )
+    }
+}
+
+impl<A: Varyings, B: Varyings, C: Varyings> Varyings for (A, B, C) {
+    fn interpolate(v: [&(A, B, C); 3], w: [f64; 3]) -> (A, B, C) {
+        (
+            A::interpolate([&v[0].0, &v[1].0, &v[2].0], w),
+            B::interpolate([&v[0].1, &v[1].1, &v[2].1], w),
+            C::interpolate([&v[0].2, &v[1].2, &v[2].2], w),
+        )
+    }
+}
+
+// What StandardVertex hands on: where the fragment is in the world, which way the surface faces
+// there and the colour of its material.
+#[derive(Debug, Clone)]
+pub struct Surface {
+    pub position: Vector,
+    pub normal: Vector,
+    pub colour: Colour,
+}
+
+impl Varyings for Surface {
+    fn interpolate(v: [&Surface; 3], w: [f64; 3]) -> Surface {
+        Surface {
+            position: Vector::interpolate([&v[0].position, &v[1].position, &v[2].position], w),
+            // Interpolated normals get shorter in the middle.
+            normal: Vector::interpolate([&v[0].normal, &v[1].normal, &v[2].normal], w).normalised(),
+            colour: Colour::interpolate([&v[0].colour, &v[1].colour, &v[2].colour], w),
+        }
+    }
+}
+
+// The vertex shader that does what rasterise does: transform by the MVP matrix and pass the world
+// position, normal and material colour on.
+#[derive(Debug, Clone, Copy)]
+pub struct StandardVertex;
+
+impl<U> VertexShader<U> for StandardVertex {
+    type Varyings = Surface;
+
+    fn vertex(&self, uniforms: &Uniforms<U>, input: &VertexInput) -> (Vector, Surface) {
+        let surface = Surface {
+            position: uniforms.model * input.position,
+            normal: transform_normal(&uniforms.normals, input.normal),
+            colour: input.material.colour.clone(),
+        };
+
+        (uniforms.mvp * input.position, surface)
+    }
+}
+
+// Shows which way surfaces face, with x, y and z going from -1 to 1 as red, green and blue from 0
+// to 1.
+#[derive(Debug, Clone, Copy)]
+pub struct NormalShader;
+
+impl<U> FragmentShader<U, Surface> for NormalShader {
+    fn fragment(&self, _: &Uniforms<U>, surface: &Surface, _: &Fragment) -> Option<Colour> {
+        let n = surface.normal;
+
+        Some(Colour::Rgba((n.x + 1.0) * 0.5, (n.y + 1.0) * 0.5, (n.z + 1.0) * 0.5, 1.0))
+    }
+}
+
+// Shows how far away things are: white at near and black at far, in world units from the camera.
+#[derive(Debug, Clone, Copy)]
+pub struct DepthShader {
+    pub near: f64,
+    pub far: f64,
+}
+
+impl<U> FragmentShader<U, Surface> for DepthShader {
+    fn fragment(&self, uniforms: &Uniforms<U>, surface: &Surface, _: &Fragment) -> Option<Colour> {
+        let distance = Vector::distance(&surface.position, &uniforms.eye);
+        let range = (self.far - self.near).max(f64::EPSILON);
+
+        Some(Colour::Grey(1.0 - ((distance - self.near) / range).clamp(0.0, 1.0)))
+    }
+}
+
+impl Device {
+    // The matrices for drawing a mesh that's been put in the world by world, along with the
+    // caller's own uniforms.
+    pub fn uniforms<'a, U>(&self, mesh: &Mesh, world: &Matrix, custom: &'a U) -> Uniforms<'a, U> {
+        let model = world * mesh.model_matrix();
+        let view = self.camera.view_matrix();
+        let projection = self.camera.projection_matrix(self.aspect());
+
+        Uniforms {
+            model,
+            view,
+            projection,
+            mvp: projection * view * model,
+            normals: normal_matrix(&model),
+            eye: self.camera.pos,
+            custom,
+        }
+    }
+
+    // Draw one mesh with the given shaders, antialiased if antialiasing is on.
+    #[allow(dead_code)]
+    pub fn draw_mesh<U, V, F>(&mut self, mesh: &Mesh, world: &Matrix, vertex: &V, fragment: &F, custom: &U)
+    where
+        V: VertexShader<U>,
+        F: FragmentShader<U, V::Varyings>,
+    {
+        let uniforms = self.uniforms(mesh, world, custom);
+        let antialiasing = self.antialiasing;
+
+        if antialiasing == Antialiasing::Off {
+            draw_mesh_into(self, &uniforms, mesh, vertex, fragment, antialiasing);
+        }
+
+        else {
+            let mut tile = self.sample_tile(antialiasing.samples());
+
+            draw_mesh_into(&mut tile, &uniforms, mesh, vertex, fragment, antialiasing);
+            self.resolve(tile);
+        }
+    }
+
+    // Draw everything (the device's meshes and the scene's) with the given shaders instead of
+    // render. Meshes out of view are skipped. Nothing goes in the ID buffer, and it's all drawn on
+    // this thread whatever threads is.
+    pub fn render_with<U, V, F>(&mut self, vertex: &V, fragment: &F, custom: &U)
+    where
+        V: VertexShader<U>,
+        F: FragmentShader<U, V::Varyings>,
+    {
+        let start = Instant::now();
+
+        self.scene.update();
+
+        let frustum = Frustum::from_matrix(&(self.camera.projection_matrix(self.aspect()) * self.camera.view_matrix()));
+
+        // Copied out so the device can be drawn into while going through them. Meshes are cheap
+        // to copy since the geometry is shared.
+        let meshes: Vec<(Matrix, Mesh)> = instances(&self.meshes, &self.scene)
+            .into_iter()
+            .map(|(_, world, m)| (world, m.clone()))
+            .collect();
+
+        // Everything goes into the same sample buffer, which is only resolved at the end.
+        let antialiasing = self.antialiasing;
+        let mut samples = if antialiasing == Antialiasing::Off { None } else { Some(self.sample_tile(antialiasing.samples())) };
+
+        for (world, m) in &meshes {
+            let (aabb, sphere) = m.bounds(&(world * m.model_matrix()));
+
+            if !frustum.intersects_sphere(&sphere) || !frustum.intersects_aabb(&aabb) {
+                self.stats.meshes_culled += 1;
+                continue;
+            }
+
+            let uniforms = self.uniforms(m, world, custom);
+
+            match &mut samples {
+                Some(tile) => draw_mesh_into(tile, &uniforms, m, vertex, fragment, antialiasing),
+                None => draw_mesh_into(self, &uniforms, m, vertex, fragment, antialiasing),
+            }
+        }
+
+        if let Some(tile) = samples {
+            self.resolve(tile);
+        }
+
+        self.stats.raster_time += start.elapsed();
+    }
+}
+
+// Draws a mesh into any target. With antialiasing on, target is a sample buffer and fragments are
+// still given the cell they're in. Back faces are culled like they are in rasterise.
+fn draw_mesh_into<T, U, V, F>(target: &mut T, uniforms: &Uniforms<U>, mesh: &Mesh, vertex: &V, fragment: &F, antialiasing: Antialiasing)
+where
+    T: Target,
+    V: VertexShader<U>,
+    F: FragmentShader<U, V::Varyings>,
+{
+    let geometry = &mesh.geometry;
+    let viewport = target.viewport();
+    let n = antialiasing.samples();
+
+    for (face, f) in geometry.faces.iter().enumerate() {
+        target.buffers().stats.triangles_submitted += 1;
+
+        let material = mesh.face_material(f);
+        let mut clip = [Vector::new(); 3];
+        let mut varyings = Vec::with_capacity(3);
+
+        for (corner, position) in clip.iter_mut().enumerate() {
+            let input = VertexInput {
+                position: geometry.vertices[f.vertices[corner]],
+                normal: f.normal,
+                face,
+                corner,
+                material: &material,
+            };
+
+            let (p, v) = vertex.vertex(uniforms, &input);
+
+            *position = p;
+            varyings.push(v);
+        }
+
+        let codes = [outcode(&clip[0]), outcode(&clip[1]), outcode(&clip[2])];
+
+        if codes[0] & codes[1] & codes[2] != 0 {
+            target.buffers().stats.triangles_culled += 1;
+            continue;
+        }
+
+        if codes[0] | codes[1] | codes[2] != 0 {
+            target.buffers().stats.triangles_clipped += 1;
+        }
+
+        let corners = [&varyings[0], &varyings[1], &varyings[2]];
+
+        for piece in clip_near_weights(&clip) {
+            let positions = piece.map(|w| weighted(&clip, w));
+            let screen = positions.map(|p| to_screen(&p, viewport));
+            let points = [(screen[0].0, screen[0].1), (screen[1].0, screen[1].1), (screen[2].0, screen[2].1)];
+
+            let area = (points[1].0 - points[0].0) * (points[2].1 - points[0].1)
+                - (points[1].1 - points[0].1) * (points[2].0 - points[0].0);
+
+            if area >= 0.0 {
+                target.buffers().stats.triangles_culled += 1;
+                continue;
+            }
+
+            let depths = [screen[0].2, screen[1].2, screen[2].2];
+            let w = [positions[0].w, positions[1].w, positions[2].w];
+
+            // When multisampling, what each cell came out as the first time one of its samples was
+            // shaded.
+            let mut shaded: HashMap<(usize, usize), Option<Colour>> = HashMap::new();
+
+            target.fill_triangle_shaded(points, depths, w, 0, |x, y, depth, b| {
+                let cell = (x / n, y / n);
+
+                if let Some(colour) = shaded.get(&cell) {
+                    return colour.clone();
+                }
+
+                // From weights of the piece's corners to weights of the original face's.
+                let weights = [
+                    piece[0][0] * b[0] + piece[1][0] * b[1] + piece[2][0] * b[2],
+                    piece[0][1] * b[0] + piece[1][1] * b[1] + piece[2][1] * b[2],
+                    piece[0][2] * b[0] + piece[1][2] * b[1] + piece[2][2] * b[2],
+                ];
+
+                let v = V::Varyings::interpolate(corners, weights);
+                let colour = fragment.fragment(uniforms, &v, &Fragment { x: cell.0, y: cell.1, depth });
+
+                if let Antialiasing::Multisample(_) = antialiasing {
+                    shaded.insert(cell, colour.clone());
+                }
+
+                colour
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Looking along a long floor, so the far end is squashed up on the screen and interpolating
+    // the distance linearly across it would be well out.
+    #[test]
+    fn varyings_are_perspective_correct() {
+        let camera = Camera::from(Vector::from(0.0, 2.0, 5.0, 1.0), Vector::new(), Vector::from(0.0, 1.0, 0.0, 0.0));
+        let mut device = Device::with_dimensions(camera, Vec::new(), Colour::Grey(0.0), (60, 30));
+        let floor = Arc::new(Geometry::plane(String::from("floor"), 20.0));
+        let shader = DepthShader { near: 1.0, far: 20.0 };
+
+        device.meshes.push(Mesh::from(floor, Vector::new(), Quaternion::identity()));
+        device.render_with(&StandardVertex, &shader, &());
+
+        let (width, height) = device.dimensions;
+        let mut checked = 0;
+
+        for y in 0..height {
+            for x in 0..width {
+                if !device.drawn[y * width + x] {
+                    continue;
+                }
+
+                // Cells are sampled in their centres, which is where this ray goes through. The
+                // rasteriser snaps the corners to sixteenths of a cell, which moves things a little
+                // this far off at this angle, but nothing like interpolating it linearly would
+                // (that's out by several times over at the back).
+                let ray = device.cell_ray(x, y);
+                let expected = -ray.origin.y / ray.direction.y;
+                let distance = shader.near + (1.0 - device.pixels[y * width + x].intensity()) * (shader.far - shader.near);
+
+                assert!((distance - expected).abs() < expected * 0.01, "({}, {}): {} instead of {}", x, y, distance, expected);
+                checked += 1;
+            }
+        }
+
+        assert!(checked > width * height / 4, "only {} cells checked", checked);
+    }
+}
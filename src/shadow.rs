@@ -0,0 +1,142 @@
+// Shadow maps. The scene is rasterised from each light's point of view into a depth buffer, which
+// then says how far the light gets in each direction. Anything further away than that from the
+// light is in shadow. Only directional lights and spotlights get them, since a point light would
+// need six.
+
+use crate::bounds::*;
+use crate::device::*;
+use crate::pipeline::*;
+use crate::render_objects::*;
+use crate::simd::*;
+use crate::stats::*;
+use crate::structures::*;
+use crate::target::*;
+
+#[derive(Debug, Clone)]
+pub struct ShadowMap {
+    // Takes world coordinates into the light's clip space.
+    pub view_projection: Matrix,
+    pub size: usize,
+    // The depth of the closest thing to the light in each texel, as normalised device z.
+    pub depth: Vec<f64>,
+}
+
+impl ShadowMap {
+    // Renders a shadow map for a light covering everything inside bounds. Gives back None for
+    // lights that can't have one.
+    pub fn render(light: &Light, bounds: &Aabb, meshes: &[(MeshId, Matrix, &Mesh)], size: usize) -> Option<ShadowMap> {
+        if bounds.is_empty() || size == 0 {
+            return None;
+        }
+
+        let centre = bounds.centre();
+        let radius = (bounds.size().magnitude() * 0.5).max(f64::EPSILON);
+
+        // Anything will do for up, as long as it isn't the way the light is pointing.
+        let up = if light.direction.y.abs() > 0.99 { Vector::direction(1.0, 0.0, 0.0) } else { Vector::direction(0.0, 1.0, 0.0) };
+
+        let view_projection = match light.kind {
+            // Looking along the light from just outside the bounds, with a box around the bounds
+            // as the view.
+            LightKind::Directional => {
+                let eye = centre - light.direction * (radius * 2.0);
+                let view = Camera::from(eye, centre, up).view_matrix();
+
+                Matrix::orthographic(-radius, radius, -radius, radius, radius, radius * 3.0) * view
+            },
+
+            LightKind::Spot => {
+                let eye = light.pos;
+                let view = Camera::from(eye, eye + light.direction, up).view_matrix();
+                let far = Vector::distance(&eye, &centre) + radius;
+
+                Matrix::perspective((light.cone * 2.0).min(3.0), 1.0, far / 1000.0, far) * view
+            },
+
+            LightKind::Point => return None,
+        };
+
+        // Drawn with the ordinary rasteriser into a tile the size of the shadow map. Only its
+        // depth buffer is wanted afterwards.
+        let mut target = Tile {
+            viewport: (size, size),
+            rect: (0, 0, size, size),
+            pixels: vec![Colour::Grey(0.0); size * size],
+            drawn: vec![false; size * size],
+            depth: vec![f64::INFINITY; size * size],
+            ids: vec![0; size * size],
+            stats: FrameStats::new(),
+        };
+
+        let mut clip_space: Vec<Vector> = Vec::new();
+
+        for (_, world, m) in meshes {
+            clip_space.clear();
+            clip_space.extend_from_slice(&m.geometry.vertices);
+            transform_vectors(&(view_projection * world * m.model_matrix()), &mut clip_space);
+
+            // Both sides of every face, since the back of something still blocks the light.
+            for f in &m.geometry.faces {
+                let clip = [clip_space[f.vertices[0]], clip_space[f.vertices[1]], clip_space[f.vertices[2]]];
+
+                if outcode(&clip[0]) & outcode(&clip[1]) & outcode(&clip[2]) != 0 {
+                    continue;
+                }
+
+                for piece in clip_near(clip) {
+                    let screen = piece.map(|v| to_screen(&v, (size, size)));
+
+                    target.fill_triangle_depth(
+                        [(screen[0].0, screen[0].1), (screen[1].0, screen[1].1), (screen[2].0, screen[2].1)],
+                        [screen[0].2, screen[1].2, screen[2].2],
+                        &Colour::Grey(1.0),
+                        0,
+                    );
+                }
+            }
+        }
+
+        Some(ShadowMap {
+            view_projection,
+            size,
+            depth: target.depth,
+        })
+    }
+
+    // How much of the light reaches a point on a surface, from 0 (completely in shadow) to 1.
+    // Texels only roughly line up with the surface, so to stop it shadowing itself the point is
+    // moved bias off the surface along its normal first. The texels up to pcf away in each
+    // direction are checked too and averaged (percentage closer filtering) to soften the edges.
+    pub fn visibility(&self, pos: &Vector, normal: &Vector, bias: f64, pcf: usize) -> f64 {
+        let clip = self.view_projection * Vector::point(pos.x + normal.x * bias, pos.y + normal.y * bias, pos.z + normal.z * bias);
+
+        if clip.w <= 0.0 {
+            return 1.0;
+        }
+
+        let (x, y, z) = to_screen(&clip, (self.size, self.size));
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        let pcf = pcf as i64;
+
+        let mut lit = 0;
+        let mut total = 0;
+
+        for dy in -pcf..=pcf {
+            for dx in -pcf..=pcf {
+                let (sx, sy) = (x + dx, y + dy);
+
+                // Outside the map counts as lit, since nothing was drawn there.
+                let blocked = sx >= 0 && sy >= 0 && (sx as usize) < self.size && (sy as usize) < self.size
+                    && z > self.depth[sy as usize * self.size + sx as usize];
+
+                if !blocked {
+                    lit += 1;
+                }
+
+                total += 1;
+            }
+        }
+
+        lit as f64 / total as f64
+    }
+}
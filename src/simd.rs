@@ -0,0 +1,142 @@
+// Transforming lots of vertices by the same matrix at once. With the simd feature on (it is by
+// default) this uses SSE for f32 and AVX for f64 on x86_64 if the CPU has it, and otherwise falls
+// back to multiplying them one at a time. Either way the answer is the same: the SIMD versions do
+// the multiplies and adds in exactly the same order as Matrix * Vector.
+
+use crate::structures::*;
+
+// Replaces every vector in vs with m * v.
+pub fn transform_vectors(m: &Matrix, vs: &mut [Vector]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            // Safe because we've just checked the CPU supports AVX.
+            unsafe { x86::transform_vectors_avx(m, vs) };
+            return;
+        }
+    }
+
+    for v in vs.iter_mut() {
+        *v = m * *v;
+    }
+}
+
+#[allow(dead_code)]
+pub fn transform_vectors32(m: &Matrix32, vs: &mut [Vector32]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        // SSE is always there on x86_64.
+        unsafe { x86::transform_vectors_sse(m, vs) };
+    }
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        for v in vs.iter_mut() {
+            *v = m * *v;
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use crate::structures::*;
+    use std::arch::x86_64::*;
+
+    // m * v is column 0 * x + column 1 * y + column 2 * z + column 3 * w, so load the columns of
+    // the matrix once and then each vector is four broadcasts, four multiplies and three adds.
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn transform_vectors_avx(m: &Matrix, vs: &mut [Vector]) {
+        let column = |j: usize| _mm256_set_pd(m.vals[3][j], m.vals[2][j], m.vals[1][j], m.vals[0][j]);
+        let (c0, c1, c2, c3) = (column(0), column(1), column(2), column(3));
+
+        for v in vs.iter_mut() {
+            // Vector is repr(C) with four f64s in order, so it can be treated as an array of them.
+            let p = v as *mut Vector as *mut f64;
+
+            let sum = _mm256_add_pd(
+                _mm256_add_pd(
+                    _mm256_add_pd(_mm256_mul_pd(c0, _mm256_set1_pd(v.x)), _mm256_mul_pd(c1, _mm256_set1_pd(v.y))),
+                    _mm256_mul_pd(c2, _mm256_set1_pd(v.z)),
+                ),
+                _mm256_mul_pd(c3, _mm256_set1_pd(v.w)),
+            );
+
+            _mm256_storeu_pd(p, sum);
+        }
+    }
+
+    #[target_feature(enable = "sse")]
+    pub unsafe fn transform_vectors_sse(m: &Matrix32, vs: &mut [Vector32]) {
+        let column = |j: usize| _mm_set_ps(m.vals[3][j], m.vals[2][j], m.vals[1][j], m.vals[0][j]);
+        let (c0, c1, c2, c3) = (column(0), column(1), column(2), column(3));
+
+        for v in vs.iter_mut() {
+            let p = v as *mut Vector32 as *mut f32;
+
+            let sum = _mm_add_ps(
+                _mm_add_ps(
+                    _mm_add_ps(_mm_mul_ps(c0, _mm_set1_ps(v.x)), _mm_mul_ps(c1, _mm_set1_ps(v.y))),
+                    _mm_mul_ps(c2, _mm_set1_ps(v.z)),
+                ),
+                _mm_mul_ps(c3, _mm_set1_ps(v.w)),
+            );
+
+            _mm_storeu_ps(p, sum);
+        }
+    }
+}
+
+// Without the simd feature both sides are the same loop, so there's nothing to check.
+#[cfg(all(test, feature = "simd"))]
+mod tests {
+    use super::*;
+
+    // Awkward numbers of all sizes and signs, always the same ones.
+    fn values(n: usize) -> Vec<f64> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+
+        (0..n).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let mantissa = (state >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+
+            mantissa * 10f64.powi((state % 13) as i32 - 6)
+        }).collect()
+    }
+
+    #[test]
+    fn matches_scalar_exactly() {
+        let v = values(16 + 4 * 257);
+        let mut m = Matrix::new();
+
+        for (i, row) in m.vals.iter_mut().enumerate() {
+            row.copy_from_slice(&v[i * 4..i * 4 + 4]);
+        }
+
+        let vectors: Vec<Vector> = v[16..].chunks(4).map(|c| Vector::from(c[0], c[1], c[2], c[3])).collect();
+        let mut simd = vectors.clone();
+
+        transform_vectors(&m, &mut simd);
+
+        for (s, v) in simd.iter().zip(&vectors) {
+            let scalar = m * *v;
+
+            assert_eq!([s.x, s.y, s.z, s.w].map(f64::to_bits), [scalar.x, scalar.y, scalar.z, scalar.w].map(f64::to_bits));
+        }
+
+        let m32 = Matrix32 { vals: m.vals.map(|row| row.map(|x| x as f32)) };
+        let vectors32: Vec<Vector32> = vectors.iter().map(|v| (*v).into()).collect();
+        let mut simd32 = vectors32.clone();
+
+        transform_vectors32(&m32, &mut simd32);
+
+        for (s, v) in simd32.iter().zip(&vectors32) {
+            let scalar = m32 * *v;
+
+            assert_eq!([s.x, s.y, s.z, s.w].map(f32::to_bits), [scalar.x, scalar.y, scalar.z, scalar.w].map(f32::to_bits));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Frame statistics. The device fills one of these in every frame so we actually have some idea
+// of where the time goes and how much work each frame is doing.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    // Which frame this is, counting from zero.
+    pub frame: u64,
+
+    // Whole meshes skipped because they were completely out of view
+    pub meshes_culled: usize,
+
+    pub triangles_submitted: usize,
+    // Triangles that were thrown away entirely before rasterising (e.g. completely off-screen)
+    pub triangles_culled: usize,
+    // Triangles that were only partially on screen and had to be cut down to the viewport
+    pub triangles_clipped: usize,
+
+    // Every write to the framebuffer counts as a shaded pixel. Overdraw is the number of those
+    // writes that landed on a pixel that had already been written to this frame.
+    pub pixels_shaded: usize,
+    pub overdraw: usize,
+
+    pub transform_time: Duration,
+    pub raster_time: Duration,
+    // Running the post-processing passes over the finished framebuffer
+    pub post_time: Duration,
+    pub present_time: Duration,
+}
+
+impl FrameStats {
+    pub fn new() -> FrameStats {
+        FrameStats::default()
+    }
+
+    // Start counting a new frame. Everything is zeroed apart from the frame number.
+    pub fn reset(&mut self) {
+        *self = FrameStats {
+            frame: self.frame + 1,
+            ..FrameStats::default()
+        };
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        self.transform_time + self.raster_time + self.post_time + self.present_time
+    }
+
+    // Overdraw as a fraction of the pixels that were actually covered. 0.0 means every pixel was
+    // only drawn once.
+    pub fn overdraw_ratio(&self) -> f64 {
+        let covered = self.pixels_shaded - self.overdraw;
+
+        if covered == 0 {
+            0.0
+        }
+
+        else {
+            self.overdraw as f64 / covered as f64
+        }
+    }
+
+    // The lines of text shown by the on-screen overlay.
+    pub fn hud_lines(&self) -> Vec<String> {
+        let frame_time = self.frame_time().as_secs_f64();
+        let fps = if frame_time > 0.0 { 1.0 / frame_time } else { 0.0 };
+
+        vec![
+            format!("frame {} | {:.2}ms ({:.0} fps)", self.frame, frame_time * 1000.0, fps),
+            format!(
+                "tris {} | culled {} | clipped {} | meshes culled {}",
+                self.triangles_submitted, self.triangles_culled, self.triangles_clipped, self.meshes_culled,
+            ),
+            format!("pixels {} | overdraw {} ({:.2}x)", self.pixels_shaded, self.overdraw, self.overdraw_ratio()),
+            format!(
+                "transform {:.2}ms | raster {:.2}ms | post {:.2}ms | present {:.2}ms",
+                self.transform_time.as_secs_f64() * 1000.0,
+                self.raster_time.as_secs_f64() * 1000.0,
+                self.post_time.as_secs_f64() * 1000.0,
+                self.present_time.as_secs_f64() * 1000.0,
+            ),
+        ]
+    }
+
+    // Machine readable version of the stats: a single line of JSON so it can be appended to a log
+    // file and picked apart later. Timings are in microseconds.
+    pub fn dump(&self) -> String {
+        format!(
+            "{{\"frame\":{},\"meshes_culled\":{},\"triangles_submitted\":{},\"triangles_culled\":{},\"triangles_clipped\":{},\
+             \"pixels_shaded\":{},\"overdraw\":{},\"transform_us\":{},\"raster_us\":{},\"post_us\":{},\"present_us\":{}}}",
+            self.frame,
+            self.meshes_culled,
+            self.triangles_submitted,
+            self.triangles_culled,
+            self.triangles_clipped,
+            self.pixels_shaded,
+            self.overdraw,
+            self.transform_time.as_micros(),
+            self.raster_time.as_micros(),
+            self.post_time.as_micros(),
+            self.present_time.as_micros(),
+        )
+    }
+}
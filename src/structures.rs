@@ -1,9 +1,43 @@
 // Definitions of all the different data structures needed for rendering
 // This defines vectors and vector arithmetic, (more to come)
+// Vector and Matrix are f64. Vector32 and Matrix32 are the same thing in f32, for when there's a
+// lot of vertices to get through and the precision doesn't matter (see simd.rs).
 
-use std::ops::{Add, Sub, Mul};
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub, SubAssign};
 
-#[derive(Debug, Clone)]
+// Everything here is Copy, so the operators are also implemented for references to save writing
+// (*a) + (*b) all over the place. This fills in the reference versions of a by-value operator.
+macro_rules! forward_ref_binop {
+    (impl $imp:ident, $method:ident for $t:ty, $u:ty) => {
+        impl<'a> $imp<$u> for &'a $t {
+            type Output = <$t as $imp<$u>>::Output;
+
+            fn $method(self, rhs: $u) -> Self::Output {
+                $imp::$method(*self, rhs)
+            }
+        }
+
+        impl<'a> $imp<&'a $u> for $t {
+            type Output = <$t as $imp<$u>>::Output;
+
+            fn $method(self, rhs: &'a $u) -> Self::Output {
+                $imp::$method(self, *rhs)
+            }
+        }
+
+        impl<'a, 'b> $imp<&'a $u> for &'b $t {
+            type Output = <$t as $imp<$u>>::Output;
+
+            fn $method(self, rhs: &'a $u) -> Self::Output {
+                $imp::$method(*self, *rhs)
+            }
+        }
+    };
+}
+
+// repr(C) so the components are laid out in order and can be loaded straight into SIMD registers.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct Vector {
     pub x: f64,
     pub y: f64,
@@ -11,11 +45,37 @@ pub struct Vector {
     pub w: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct Matrix {
     pub vals: [[f64; 4]; 4],
 }
 
+// A rotation. Unlike Euler angles these don't suffer from gimbal lock and can be smoothly
+// interpolated between. Should always be normalised if it's being used as a rotation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Vector32 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Matrix32 {
+    pub vals: [[f32; 4]; 4],
+}
+
 impl Vector {
     pub fn new() -> Vector {
         Vector {
@@ -28,10 +88,10 @@ impl Vector {
 
     pub fn from(x: f64, y: f64, z: f64, w: f64) -> Vector {
         Vector {
-            x: x,
-            y: y,
-            z: z,
-            w: w,
+            x,
+            y,
+            z,
+            w,
         }
     }
 
@@ -41,7 +101,7 @@ impl Vector {
 
     // Keep direction of vector but set magnitude to 1
     pub fn normalise(&mut self) {
-        let m = self.clone().magnitude();
+        let m = self.magnitude();
 
         self.x /= m;
         self.y /= m;
@@ -49,18 +109,60 @@ impl Vector {
     }
 
     pub fn normalised(self) -> Vector {
-        let mut v = self.clone();
+        let mut v = self;
 
         v.normalise();
         v
     }
 
     pub fn dot_product(v1: &Vector, v2: &Vector) -> f64 {
-        return v1.x*v2.x+v1.y*v2.y+v1.z*v2.z;
+        v1.x*v2.x+v1.y*v2.y+v1.z*v2.z
     }
 
     pub fn cross_product(v1: &Vector, v2: &Vector) -> Vector {
-        return Vector::from(v1.y*v2.z-v1.z*v2.y, v1.z*v2.x-v1.x*v2.z, v1.x*v2.y-v1.y*v2.x, 0.0);
+        Vector::from(v1.y*v2.z-v1.z*v2.y, v1.z*v2.x-v1.x*v2.z, v1.x*v2.y-v1.y*v2.x, 0.0)
+    }
+
+    // A position (w = 1) and a direction (w = 0), for saying what you mean.
+    pub fn point(x: f64, y: f64, z: f64) -> Vector {
+        Vector::from(x, y, z, 1.0)
+    }
+
+    pub fn direction(x: f64, y: f64, z: f64) -> Vector {
+        Vector::from(x, y, z, 0.0)
+    }
+
+    pub fn distance(v1: &Vector, v2: &Vector) -> f64 {
+        (v1 - v2).magnitude()
+    }
+
+    // Linear interpolation: t = 0 gives v1 and t = 1 gives v2.
+    pub fn lerp(v1: &Vector, v2: &Vector, t: f64) -> Vector {
+        Vector::from(
+            v1.x + (v2.x - v1.x) * t,
+            v1.y + (v2.y - v1.y) * t,
+            v1.z + (v2.z - v1.z) * t,
+            v1.w + (v2.w - v1.w) * t,
+        )
+    }
+
+    // Reflect the vector off a surface with the given (normalised) normal.
+    pub fn reflect(self, normal: &Vector) -> Vector {
+        let d = Vector::dot_product(&self, normal);
+
+        Vector::from(self.x - 2.0*d*normal.x, self.y - 2.0*d*normal.y, self.z - 2.0*d*normal.z, self.w)
+    }
+
+    // Divide through by w to get back to ordinary 3D coordinates (e.g. after a perspective
+    // projection). Directions (w = 0) are left alone.
+    pub fn homogenised(self) -> Vector {
+        if self.w == 0.0 {
+            self
+        }
+
+        else {
+            Vector::from(self.x / self.w, self.y / self.w, self.z / self.w, 1.0)
+        }
     }
 }
 
@@ -72,11 +174,31 @@ impl Mul<f64> for Vector {
     }
 }
 
+impl Div<f64> for Vector {
+    type Output = Vector;
+
+    fn div(self, rhs: f64) -> Vector {
+        Vector::from(self.x / rhs, self.y / rhs, self.z / rhs, self.w)
+    }
+}
+
+// Component-wise multiplication, e.g. for scaling by different amounts along each axis or
+// multiplying colours together.
+impl Mul<Vector> for Vector {
+    type Output = Vector;
+
+    fn mul(self, rhs: Vector) -> Vector {
+        Vector::from(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z, self.w)
+    }
+}
+
+// Adding and subtracting also does w, so the usual rules work out: point - point is a direction,
+// point + direction is a point, and direction + direction is a direction.
 impl Add<Vector> for Vector {
     type Output = Vector;
 
     fn add(self, rhs: Vector) -> Vector {
-        Vector::from(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z, self.w)
+        Vector::from(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z, self.w + rhs.w)
     }
 }
 
@@ -84,10 +206,71 @@ impl Sub<Vector> for Vector {
     type Output = Vector;
 
     fn sub(self, rhs: Vector) -> Vector {
-        Vector::from(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z, self.w)
+        Vector::from(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z, self.w - rhs.w)
+    }
+}
+
+impl Neg for Vector {
+    type Output = Vector;
+
+    fn neg(self) -> Vector {
+        Vector::from(-self.x, -self.y, -self.z, self.w)
+    }
+}
+
+impl Neg for &Vector {
+    type Output = Vector;
+
+    fn neg(self) -> Vector {
+        -*self
+    }
+}
+
+// v[0], v[1], v[2] and v[3] are x, y, z and w, for code that loops over the axes.
+impl Index<usize> for Vector {
+    type Output = f64;
+
+    fn index(&self, i: usize) -> &f64 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            3 => &self.w,
+            _ => panic!("Vector index out of range: {}", i),
+        }
+    }
+}
+
+impl AddAssign<Vector> for Vector {
+    fn add_assign(&mut self, rhs: Vector) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign<Vector> for Vector {
+    fn sub_assign(&mut self, rhs: Vector) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign<f64> for Vector {
+    fn mul_assign(&mut self, rhs: f64) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign<f64> for Vector {
+    fn div_assign(&mut self, rhs: f64) {
+        *self = *self / rhs;
     }
 }
 
+forward_ref_binop!(impl Mul, mul for Vector, f64);
+forward_ref_binop!(impl Div, div for Vector, f64);
+forward_ref_binop!(impl Mul, mul for Vector, Vector);
+forward_ref_binop!(impl Add, add for Vector, Vector);
+forward_ref_binop!(impl Sub, sub for Vector, Vector);
+
 impl Matrix {
     pub fn new() -> Matrix {
         Matrix {
@@ -95,6 +278,24 @@ impl Matrix {
         }
     }
 
+    pub fn identity() -> Matrix {
+        Matrix {
+            vals: [[1.0, 0.0, 0.0, 0.0],
+                   [0.0, 1.0, 0.0, 0.0],
+                   [0.0, 0.0, 1.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    pub fn scale(v: Vector) -> Matrix {
+        Matrix {
+            vals: [[v.x, 0.0, 0.0, 0.0],
+                   [0.0, v.y, 0.0, 0.0],
+                   [0.0, 0.0, v.z, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
     pub fn translation(v: Vector) -> Matrix {
         Matrix {
             vals: [[1.0, 0.0, 0.0, v.x],
@@ -115,11 +316,138 @@ impl Matrix {
                 [angle.cos()+ux.powf(2.0)*(1.0-angle.cos()), ux*uy*(1.0-angle.cos())-uz*angle.sin(), ux*uz*(1.0-angle.cos())+uy*angle.sin(), 0.0],
                 [uy*ux*(1.0-angle.cos())+uz*angle.sin(), angle.cos()+uy.powf(2.0)*(1.0-angle.cos()), uy*uz*(1.0-angle.cos())-ux*angle.sin(), 0.0],
                 [uz*ux*(1.0-angle.cos())-uy*angle.sin(), uz*uy*(1.0-angle.cos())+ux*angle.sin(), angle.cos()+uz.powf(2.0)*(1.0-angle.cos()), 0.0],
-                [0.0, 0.0, 0.0, 0.0]
+                [0.0, 0.0, 0.0, 1.0]
             ]
         }
     }
 
+    // Rotations about each of the axes. All angles are in radians, anticlockwise when looking down
+    // the axis towards the origin.
+    pub fn rotation_x(angle: f64) -> Matrix {
+        let (s, c) = angle.sin_cos();
+
+        Matrix {
+            vals: [[1.0, 0.0, 0.0, 0.0],
+                   [0.0, c, -s, 0.0],
+                   [0.0, s, c, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    pub fn rotation_y(angle: f64) -> Matrix {
+        let (s, c) = angle.sin_cos();
+
+        Matrix {
+            vals: [[c, 0.0, s, 0.0],
+                   [0.0, 1.0, 0.0, 0.0],
+                   [-s, 0.0, c, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    pub fn rotation_z(angle: f64) -> Matrix {
+        let (s, c) = angle.sin_cos();
+
+        Matrix {
+            vals: [[c, -s, 0.0, 0.0],
+                   [s, c, 0.0, 0.0],
+                   [0.0, 0.0, 1.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    // Euler angles (pitch about x, yaw about y, roll about z). Roll is applied first, then pitch,
+    // then yaw.
+    pub fn euler(pitch: f64, yaw: f64, roll: f64) -> Matrix {
+        Matrix::rotation_y(yaw) * Matrix::rotation_x(pitch) * Matrix::rotation_z(roll)
+    }
+
+    pub fn transpose(&self) -> Matrix {
+        let mut vals = [[0f64; 4]; 4];
+
+        for (i, row) in vals.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
+                *val = self.vals[j][i];
+            }
+        }
+
+        Matrix { vals }
+    }
+
+    // The 2x2 determinants that the determinant and inverse are both built out of. s uses the top
+    // two rows and c the bottom two.
+    fn minors(&self) -> ([f64; 6], [f64; 6]) {
+        let m = &self.vals;
+
+        let s = [
+            m[0][0]*m[1][1] - m[1][0]*m[0][1],
+            m[0][0]*m[1][2] - m[1][0]*m[0][2],
+            m[0][0]*m[1][3] - m[1][0]*m[0][3],
+            m[0][1]*m[1][2] - m[1][1]*m[0][2],
+            m[0][1]*m[1][3] - m[1][1]*m[0][3],
+            m[0][2]*m[1][3] - m[1][2]*m[0][3],
+        ];
+
+        let c = [
+            m[2][0]*m[3][1] - m[3][0]*m[2][1],
+            m[2][0]*m[3][2] - m[3][0]*m[2][2],
+            m[2][0]*m[3][3] - m[3][0]*m[2][3],
+            m[2][1]*m[3][2] - m[3][1]*m[2][2],
+            m[2][1]*m[3][3] - m[3][1]*m[2][3],
+            m[2][2]*m[3][3] - m[3][2]*m[2][3],
+        ];
+
+        (s, c)
+    }
+
+    pub fn determinant(&self) -> f64 {
+        let (s, c) = self.minors();
+
+        s[0]*c[5] - s[1]*c[4] + s[2]*c[3] + s[3]*c[2] - s[4]*c[1] + s[5]*c[0]
+    }
+
+    // None if the matrix can't be inverted (the determinant is zero, e.g. a scale by 0).
+    pub fn inverse(&self) -> Option<Matrix> {
+        let det = self.determinant();
+
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+
+        let (s, c) = self.minors();
+        let m = &self.vals;
+        let d = 1.0 / det;
+
+        Some(Matrix {
+            vals: [
+                [
+                    ( m[1][1]*c[5] - m[1][2]*c[4] + m[1][3]*c[3]) * d,
+                    (-m[0][1]*c[5] + m[0][2]*c[4] - m[0][3]*c[3]) * d,
+                    ( m[3][1]*s[5] - m[3][2]*s[4] + m[3][3]*s[3]) * d,
+                    (-m[2][1]*s[5] + m[2][2]*s[4] - m[2][3]*s[3]) * d,
+                ],
+                [
+                    (-m[1][0]*c[5] + m[1][2]*c[2] - m[1][3]*c[1]) * d,
+                    ( m[0][0]*c[5] - m[0][2]*c[2] + m[0][3]*c[1]) * d,
+                    (-m[3][0]*s[5] + m[3][2]*s[2] - m[3][3]*s[1]) * d,
+                    ( m[2][0]*s[5] - m[2][2]*s[2] + m[2][3]*s[1]) * d,
+                ],
+                [
+                    ( m[1][0]*c[4] - m[1][1]*c[2] + m[1][3]*c[0]) * d,
+                    (-m[0][0]*c[4] + m[0][1]*c[2] - m[0][3]*c[0]) * d,
+                    ( m[3][0]*s[4] - m[3][1]*s[2] + m[3][3]*s[0]) * d,
+                    (-m[2][0]*s[4] + m[2][1]*s[2] - m[2][3]*s[0]) * d,
+                ],
+                [
+                    (-m[1][0]*c[3] + m[1][1]*c[1] - m[1][2]*c[0]) * d,
+                    ( m[0][0]*c[3] - m[0][1]*c[1] + m[0][2]*c[0]) * d,
+                    (-m[3][0]*s[3] + m[3][1]*s[1] - m[3][2]*s[0]) * d,
+                    ( m[2][0]*s[3] - m[2][1]*s[1] + m[2][2]*s[0]) * d,
+                ],
+            ]
+        })
+    }
+
     pub fn perspective(angle: f64, ratio: f64, near: f64, far: f64) -> Matrix {
         // (THA stands for tan-half-angle - thought the variable name was unwieldy
         let tha = (angle/2f64).tan();
@@ -131,6 +459,17 @@ impl Matrix {
                    [0.0, 0.0, -1.0, 0.0]]
         }
     }
+
+    // Orthographic projection of the box from (left, bottom, -near) to (right, top, -far) in
+    // camera space, so nothing gets smaller with distance.
+    pub fn orthographic(left: f64, right: f64, bottom: f64, top: f64, near: f64, far: f64) -> Matrix {
+        Matrix {
+            vals: [[2.0/(right - left), 0.0, 0.0, -(right + left)/(right - left)],
+                   [0.0, 2.0/(top - bottom), 0.0, -(top + bottom)/(top - bottom)],
+                   [0.0, 0.0, -2.0/(far - near), -(far + near)/(far - near)],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
 }
 
 impl Mul<Vector> for Matrix {
@@ -155,17 +494,581 @@ impl Mul<Matrix> for Matrix {
         // Watch as I condense matrix multiplication into the worst thing ever:
         // A triple nested for loop. Fun for the whole family! That is if you have a family of
         // masochists, though frankly I think 'masochist' and 'programmer' mean the same thing.
-        for i in 0..4 {
-            for j in 0..4 {
+        for (i, row) in vals.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
                 for k in 0..4 {
-                    vals[i][j] += self.vals[i][k]*rhs.vals[k][j];
+                    *val += self.vals[i][k]*rhs.vals[k][j];
                 }
             }
         }
 
         // Bruh
         Matrix {
-            vals: vals,
+            vals,
+        }
+    }
+}
+
+forward_ref_binop!(impl Mul, mul for Matrix, Vector);
+forward_ref_binop!(impl Mul, mul for Matrix, Matrix);
+
+impl Quaternion {
+    // No rotation at all.
+    pub fn identity() -> Quaternion {
+        Quaternion::from(1.0, 0.0, 0.0, 0.0)
+    }
+
+    pub fn from(w: f64, x: f64, y: f64, z: f64) -> Quaternion {
+        Quaternion { w, x, y, z }
+    }
+
+    // Same as Matrix::rotation: angle radians about axis.
+    pub fn from_axis_angle(axis: Vector, angle: f64) -> Quaternion {
+        let a = axis.normalised();
+        let (s, c) = (angle / 2.0).sin_cos();
+
+        Quaternion::from(c, a.x * s, a.y * s, a.z * s)
+    }
+
+    // Same order as Matrix::euler: roll about z first, then pitch about x, then yaw about y.
+    pub fn from_euler(pitch: f64, yaw: f64, roll: f64) -> Quaternion {
+        Quaternion::from_axis_angle(Vector::direction(0.0, 1.0, 0.0), yaw)
+            * Quaternion::from_axis_angle(Vector::direction(1.0, 0.0, 0.0), pitch)
+            * Quaternion::from_axis_angle(Vector::direction(0.0, 0.0, 1.0), roll)
+    }
+
+    // The rotation part of a matrix (the top left 3x3), which must not have any scaling in it.
+    pub fn from_matrix(m: &Matrix) -> Quaternion {
+        let m = &m.vals;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        // Work out whichever component is biggest first to avoid dividing by something tiny.
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion::from(0.25 * s, (m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s)
+        }
+
+        else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quaternion::from((m[2][1] - m[1][2]) / s, 0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s)
+        }
+
+        else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quaternion::from((m[0][2] - m[2][0]) / s, (m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s)
+        }
+
+        else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quaternion::from((m[1][0] - m[0][1]) / s, (m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s)
+        };
+
+        q.normalised()
+    }
+
+    pub fn to_matrix(self) -> Matrix {
+        let (w, x, y, z) = (self.w, self.x, self.y, self.z);
+
+        Matrix {
+            vals: [[1.0 - 2.0*(y*y + z*z), 2.0*(x*y - w*z), 2.0*(x*z + w*y), 0.0],
+                   [2.0*(x*y + w*z), 1.0 - 2.0*(x*x + z*z), 2.0*(y*z - w*x), 0.0],
+                   [2.0*(x*z - w*y), 2.0*(y*z + w*x), 1.0 - 2.0*(x*x + y*y), 0.0],
+                   [0.0, 0.0, 0.0, 1.0]]
+        }
+    }
+
+    // (axis, angle) that this rotates by. The axis is arbitrary if there's no rotation.
+    pub fn to_axis_angle(self) -> (Vector, f64) {
+        let q = if self.w < 0.0 { -self } else { self };
+        let s = (1.0 - q.w * q.w).max(0.0).sqrt();
+
+        if s < 1e-9 {
+            (Vector::direction(1.0, 0.0, 0.0), 0.0)
+        }
+
+        else {
+            (Vector::direction(q.x / s, q.y / s, q.z / s), 2.0 * q.w.min(1.0).acos())
+        }
+    }
+
+    pub fn magnitude(&self) -> f64 {
+        Quaternion::dot_product(self, self).sqrt()
+    }
+
+    pub fn normalise(&mut self) {
+        let m = self.magnitude();
+
+        self.w /= m;
+        self.x /= m;
+        self.y /= m;
+        self.z /= m;
+    }
+
+    pub fn normalised(self) -> Quaternion {
+        let mut q = self;
+
+        q.normalise();
+        q
+    }
+
+    // For a normalised quaternion this is also the inverse, i.e. the opposite rotation.
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion::from(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn dot_product(q1: &Quaternion, q2: &Quaternion) -> f64 {
+        q1.w*q2.w + q1.x*q2.x + q1.y*q2.y + q1.z*q2.z
+    }
+
+    // Rotate a vector. w is left as it is, so points stay points and directions stay directions.
+    pub fn rotate(&self, v: Vector) -> Vector {
+        let p = *self * Quaternion::from(0.0, v.x, v.y, v.z) * self.conjugate();
+
+        Vector::from(p.x, p.y, p.z, v.w)
+    }
+
+    // Cheap interpolation: straight line between the two then normalised. The speed isn't
+    // constant but it's usually close enough.
+    pub fn nlerp(q1: &Quaternion, q2: &Quaternion, t: f64) -> Quaternion {
+        // q and -q are the same rotation, so go whichever way round is shorter.
+        let q2 = if Quaternion::dot_product(q1, q2) < 0.0 { -*q2 } else { *q2 };
+
+        Quaternion::from(
+            q1.w + (q2.w - q1.w) * t,
+            q1.x + (q2.x - q1.x) * t,
+            q1.y + (q2.y - q1.y) * t,
+            q1.z + (q2.z - q1.z) * t,
+        ).normalised()
+    }
+
+    // Spherical interpolation: rotates from q1 to q2 at a constant speed.
+    pub fn slerp(q1: &Quaternion, q2: &Quaternion, t: f64) -> Quaternion {
+        let mut d = Quaternion::dot_product(q1, q2);
+        let q2 = if d < 0.0 { d = -d; -*q2 } else { *q2 };
+
+        // Nearly the same rotation, where sin(theta) gets too small to divide by.
+        if d > 0.9995 {
+            return Quaternion::nlerp(q1, &q2, t);
+        }
+
+        let theta = d.min(1.0).acos();
+        let s = theta.sin();
+        let a = ((1.0 - t) * theta).sin() / s;
+        let b = (t * theta).sin() / s;
+
+        Quaternion::from(a*q1.w + b*q2.w, a*q1.x + b*q2.x, a*q1.y + b*q2.y, a*q1.z + b*q2.z)
+    }
+}
+
+// Composing rotations: (a * b) rotates by b first and then by a, same as with matrices.
+impl Mul<Quaternion> for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, rhs: Quaternion) -> Quaternion {
+        Quaternion::from(
+            self.w*rhs.w - self.x*rhs.x - self.y*rhs.y - self.z*rhs.z,
+            self.w*rhs.x + self.x*rhs.w + self.y*rhs.z - self.z*rhs.y,
+            self.w*rhs.y - self.x*rhs.z + self.y*rhs.w + self.z*rhs.x,
+            self.w*rhs.z + self.x*rhs.y - self.y*rhs.x + self.z*rhs.w,
+        )
+    }
+}
+
+impl Neg for Quaternion {
+    type Output = Quaternion;
+
+    fn neg(self) -> Quaternion {
+        Quaternion::from(-self.w, -self.x, -self.y, -self.z)
+    }
+}
+
+forward_ref_binop!(impl Mul, mul for Quaternion, Quaternion);
+
+impl Vector32 {
+    pub fn new() -> Vector32 {
+        Vector32 {
+            x: 0f32,
+            y: 0f32,
+            z: 0f32,
+            w: 1f32,
+        }
+    }
+
+    pub fn from(x: f32, y: f32, z: f32, w: f32) -> Vector32 {
+        Vector32 { x, y, z, w }
+    }
+
+    pub fn magnitude(self) -> f32 {
+        (self.x*self.x + self.y*self.y + self.z*self.z).sqrt()
+    }
+
+    pub fn normalised(self) -> Vector32 {
+        let m = self.magnitude();
+
+        Vector32::from(self.x / m, self.y / m, self.z / m, self.w)
+    }
+
+    pub fn dot_product(v1: &Vector32, v2: &Vector32) -> f32 {
+        v1.x*v2.x+v1.y*v2.y+v1.z*v2.z
+    }
+
+    pub fn cross_product(v1: &Vector32, v2: &Vector32) -> Vector32 {
+        Vector32::from(v1.y*v2.z-v1.z*v2.y, v1.z*v2.x-v1.x*v2.z, v1.x*v2.y-v1.y*v2.x, 0.0)
+    }
+}
+
+impl Mul<f32> for Vector32 {
+    type Output = Vector32;
+
+    fn mul(self, rhs: f32) -> Vector32 {
+        Vector32::from(self.x * rhs, self.y * rhs, self.z * rhs, self.w)
+    }
+}
+
+impl Add<Vector32> for Vector32 {
+    type Output = Vector32;
+
+    fn add(self, rhs: Vector32) -> Vector32 {
+        Vector32::from(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z, self.w)
+    }
+}
+
+impl Sub<Vector32> for Vector32 {
+    type Output = Vector32;
+
+    fn sub(self, rhs: Vector32) -> Vector32 {
+        Vector32::from(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z, self.w)
+    }
+}
+
+forward_ref_binop!(impl Mul, mul for Vector32, f32);
+forward_ref_binop!(impl Add, add for Vector32, Vector32);
+forward_ref_binop!(impl Sub, sub for Vector32, Vector32);
+
+impl Matrix32 {
+    pub fn new() -> Matrix32 {
+        Matrix32 {
+            vals: [[0f32; 4]; 4],
+        }
+    }
+}
+
+impl Mul<Vector32> for Matrix32 {
+    type Output = Vector32;
+
+    fn mul(self, rhs: Vector32) -> Vector32 {
+        Vector32 {
+            x: self.vals[0][0]*rhs.x + self.vals[0][1]*rhs.y + self.vals[0][2]*rhs.z + self.vals[0][3]*rhs.w,
+            y: self.vals[1][0]*rhs.x + self.vals[1][1]*rhs.y + self.vals[1][2]*rhs.z + self.vals[1][3]*rhs.w,
+            z: self.vals[2][0]*rhs.x + self.vals[2][1]*rhs.y + self.vals[2][2]*rhs.z + self.vals[2][3]*rhs.w,
+            w: self.vals[3][0]*rhs.x + self.vals[3][1]*rhs.y + self.vals[3][2]*rhs.z + self.vals[3][3]*rhs.w,
+        }
+    }
+}
+
+impl Mul<Matrix32> for Matrix32 {
+    type Output = Matrix32;
+
+    fn mul(self, rhs: Matrix32) -> Matrix32 {
+        let mut vals = [[0f32; 4]; 4];
+
+        for (i, row) in vals.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
+                for k in 0..4 {
+                    *val += self.vals[i][k]*rhs.vals[k][j];
+                }
+            }
+        }
+
+        Matrix32 { vals }
+    }
+}
+
+forward_ref_binop!(impl Mul, mul for Matrix32, Vector32);
+forward_ref_binop!(impl Mul, mul for Matrix32, Matrix32);
+
+// Converting between the two precisions. Use .into(), since Vector32::from is already taken.
+impl From<Vector> for Vector32 {
+    fn from(v: Vector) -> Vector32 {
+        Vector32::from(v.x as f32, v.y as f32, v.z as f32, v.w as f32)
+    }
+}
+
+impl From<Vector32> for Vector {
+    fn from(v: Vector32) -> Vector {
+        Vector::from(v.x as f64, v.y as f64, v.z as f64, v.w as f64)
+    }
+}
+
+impl From<Matrix> for Matrix32 {
+    fn from(m: Matrix) -> Matrix32 {
+        Matrix32 {
+            vals: m.vals.map(|row| row.map(|v| v as f32)),
+        }
+    }
+}
+
+impl From<Matrix32> for Matrix {
+    fn from(m: Matrix32) -> Matrix {
+        Matrix {
+            vals: m.vals.map(|row| row.map(|v| v as f64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tiny xorshift generator, so the tests get lots of different inputs but always the same
+    // ones from run to run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        // Between -size and size.
+        fn range(&mut self, size: f64) -> f64 {
+            (self.next() * 2.0 - 1.0) * size
+        }
+
+        fn vector(&mut self, w: f64) -> Vector {
+            Vector::from(self.range(10.0), self.range(10.0), self.range(10.0), w)
+        }
+
+        fn matrix(&mut self) -> Matrix {
+            let mut m = Matrix::new();
+
+            for row in m.vals.iter_mut() {
+                for val in row.iter_mut() {
+                    *val = self.range(5.0);
+                }
+            }
+
+            m
+        }
+    }
+
+    const RUNS: usize = 1000;
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() <= tolerance * (1.0 + a.abs().max(b.abs()))
+    }
+
+    fn matrices_close(a: &Matrix, b: &Matrix, tolerance: f64) -> bool {
+        (0..4).all(|i| (0..4).all(|j| close(a.vals[i][j], b.vals[i][j], tolerance)))
+    }
+
+    fn vectors_close(a: &Vector, b: &Vector, tolerance: f64) -> bool {
+        close(a.x, b.x, tolerance) && close(a.y, b.y, tolerance) && close(a.z, b.z, tolerance) && close(a.w, b.w, tolerance)
+    }
+
+    // q and -q are the same rotation, so either counts.
+    fn rotations_close(a: &Quaternion, b: &Quaternion, tolerance: f64) -> bool {
+        close(Quaternion::dot_product(a, b).abs(), 1.0, tolerance)
+    }
+
+    #[test]
+    fn inverse_undoes_matrix() {
+        let mut random = Random(0x1234_5678);
+        let identity = Matrix::identity();
+
+        for _ in 0..RUNS {
+            let m = random.matrix();
+
+            // Nearly singular ones lose too much precision to compare against.
+            if m.determinant().abs() < 1e-2 {
+                continue;
+            }
+
+            let inverse = m.inverse().unwrap();
+
+            assert!(matrices_close(&(m * inverse), &identity, 1e-8), "{:?}", m);
+            assert!(matrices_close(&(inverse * m), &identity, 1e-8), "{:?}", m);
+        }
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let mut random = Random(0x9e37_79b9);
+
+        for _ in 0..RUNS {
+            // Two rows the same.
+            let mut m = random.matrix();
+            m.vals[1] = m.vals[0];
+
+            assert!(m.inverse().is_none(), "{:?}", m);
+
+            // A row of zeroes.
+            let mut m = random.matrix();
+            m.vals[3] = [0.0; 4];
+
+            assert!(m.inverse().is_none(), "{:?}", m);
+        }
+
+        assert!(Matrix::scale(Vector::direction(1.0, 0.0, 1.0)).inverse().is_none());
+        assert!(Matrix::new().inverse().is_none());
+    }
+
+    #[test]
+    fn determinant_of_product() {
+        let mut random = Random(0xdead_beef);
+
+        for _ in 0..RUNS {
+            let (a, b) = (random.matrix(), random.matrix());
+
+            assert!(close((a * b).determinant(), a.determinant() * b.determinant(), 1e-9));
+        }
+    }
+
+    #[test]
+    fn transpose_twice() {
+        let mut random = Random(0xcafe_f00d);
+
+        for _ in 0..RUNS {
+            let m = random.matrix();
+
+            assert_eq!(m.transpose().transpose().vals, m.vals);
+        }
+    }
+
+    #[test]
+    fn rotations_keep_w() {
+        let mut random = Random(0x0bad_cafe);
+
+        for _ in 0..RUNS {
+            let angle = random.range(10.0);
+            let rotations = [
+                Matrix::rotation(angle, random.vector(0.0)),
+                Matrix::rotation_x(angle),
+                Matrix::rotation_y(angle),
+                Matrix::rotation_z(angle),
+                Matrix::euler(random.range(10.0), random.range(10.0), random.range(10.0)),
+            ];
+
+            for r in rotations.iter() {
+                assert_eq!(r.vals[3], [0.0, 0.0, 0.0, 1.0]);
+                assert!((0..3).all(|i| r.vals[i][3] == 0.0));
+
+                let (point, direction) = (random.vector(1.0), random.vector(0.0));
+
+                assert_eq!((r * point).w, 1.0);
+                assert_eq!((r * direction).w, 0.0);
+                assert!(close((r * point).magnitude(), point.magnitude(), 1e-9));
+            }
+        }
+    }
+
+    #[test]
+    fn lerp_ends_and_middle() {
+        let mut random = Random(0x5eed_1e55);
+
+        for _ in 0..RUNS {
+            let (a, b) = (random.vector(1.0), random.vector(1.0));
+            let t = random.next();
+            let p = Vector::lerp(&a, &b, t);
+
+            assert!(vectors_close(&Vector::lerp(&a, &b, 0.0), &a, 1e-12));
+            assert!(vectors_close(&Vector::lerp(&a, &b, 1.0), &b, 1e-12));
+            assert!(vectors_close(&Vector::lerp(&a, &b, t), &Vector::lerp(&b, &a, 1.0 - t), 1e-9));
+            // Somewhere on the line between them, t of the way along.
+            assert!(close(Vector::distance(&a, &p), t * Vector::distance(&a, &b), 1e-9));
+            assert!(close(Vector::distance(&a, &p) + Vector::distance(&p, &b), Vector::distance(&a, &b), 1e-9));
+        }
+    }
+
+    #[test]
+    fn reflect_twice() {
+        let mut random = Random(0xfeed_face);
+
+        for _ in 0..RUNS {
+            let v = random.vector(0.0);
+            let normal = random.vector(0.0).normalised();
+            let reflected = v.reflect(&normal);
+
+            assert!(vectors_close(&reflected.reflect(&normal), &v, 1e-9));
+            assert!(close(reflected.magnitude(), v.magnitude(), 1e-9));
+            // The part along the normal flips and the rest stays the same.
+            assert!(close(Vector::dot_product(&reflected, &normal), -Vector::dot_product(&v, &normal), 1e-9));
+            assert_eq!(reflected.w, v.w);
+        }
+    }
+
+    #[test]
+    fn distance_is_a_metric() {
+        let mut random = Random(0x0dd_ba11);
+
+        for _ in 0..RUNS {
+            let (a, b, c) = (random.vector(1.0), random.vector(1.0), random.vector(1.0));
+
+            assert_eq!(Vector::distance(&a, &a), 0.0);
+            assert!(close(Vector::distance(&a, &b), Vector::distance(&b, &a), 1e-12));
+            assert!(Vector::distance(&a, &c) <= Vector::distance(&a, &b) + Vector::distance(&b, &c) + 1e-9);
+            assert!(close(Vector::distance(&a, &b), (b - a).magnitude(), 1e-12));
+        }
+    }
+
+    #[test]
+    fn quaternions_match_matrices() {
+        let mut random = Random(0xa11_a9e5);
+
+        for _ in 0..RUNS {
+            let (axis, angle) = (random.vector(0.0), random.range(10.0));
+            let q = Quaternion::from_axis_angle(axis, angle);
+            let m = q.to_matrix();
+            let v = random.vector(1.0);
+
+            assert!(matrices_close(&m, &Matrix::rotation(angle, axis), 1e-9));
+            assert!(vectors_close(&q.rotate(v), &(m * v), 1e-9));
+            assert!(rotations_close(&Quaternion::from_matrix(&m), &q, 1e-9));
+
+            // The angle can come back different (e.g. -a about -axis), but it's the same rotation.
+            let (back_axis, back_angle) = q.to_axis_angle();
+
+            assert!(matrices_close(&Quaternion::from_axis_angle(back_axis, back_angle).to_matrix(), &m, 1e-9));
+        }
+    }
+
+    #[test]
+    fn slerp_ends_and_middle() {
+        let mut random = Random(0x0051_e2ba);
+
+        for _ in 0..RUNS {
+            let q1 = Quaternion::from_axis_angle(random.vector(0.0), random.range(3.0));
+            let q2 = Quaternion::from_axis_angle(random.vector(0.0), random.range(3.0));
+            let middle = Quaternion::slerp(&q1, &q2, 0.5);
+
+            assert!(rotations_close(&Quaternion::slerp(&q1, &q2, 0.0), &q1, 1e-9));
+            assert!(rotations_close(&Quaternion::slerp(&q1, &q2, 1.0), &q2, 1e-9));
+            assert!(close(middle.magnitude(), 1.0, 1e-9));
+            // Halfway round, so just as far from either end.
+            assert!(close(Quaternion::dot_product(&q1, &middle).abs(), Quaternion::dot_product(&middle, &q2).abs(), 1e-9));
+            assert!(Quaternion::dot_product(&q1, &middle).abs() >= Quaternion::dot_product(&q1, &q2).abs() - 1e-9);
+        }
+    }
+
+    #[test]
+    fn normalising() {
+        let mut random = Random(0x0c0f_fee5);
+
+        for _ in 0..RUNS {
+            let q = Quaternion::from(random.range(5.0), random.range(5.0), random.range(5.0), random.range(5.0));
+            let n = q.normalised();
+            let v = random.vector(0.0);
+
+            assert!(close(n.magnitude(), 1.0, 1e-12));
+            assert!(close(Quaternion::dot_product(&q, &n), q.magnitude(), 1e-9));
+            // Normalised ones are rotations, so lengths stay the same, and stay normalised when
+            // they're combined.
+            assert!(close(n.rotate(v).magnitude(), v.magnitude(), 1e-9));
+            assert!(close((n * n * n).magnitude(), 1.0, 1e-9));
+            assert!(rotations_close(&(n * n.conjugate()), &Quaternion::identity(), 1e-12));
         }
     }
 }
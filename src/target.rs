@@ -0,0 +1,455 @@
+// Anything that can be drawn into: the whole screen on the device, or just one tile of it when
+// rendering in parallel. All of the pixel pushing (points, lines, triangles) lives here so that it
+// does exactly the same thing in both cases.
+
+use crate::raster::*;
+use crate::render_objects::*;
+use crate::stats::*;
+use std::cmp;
+
+// How to draw lines with draw_line_styled, mostly for wireframes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineStyle {
+    Solid,
+    Dashed(usize, usize), // (pixels on, pixels off)
+    Pattern(u16),         // Repeats every 16 pixels, lowest bit first. 1 means draw.
+}
+
+impl LineStyle {
+    // Whether the nth pixel along a line should be drawn.
+    pub fn is_on(self, n: usize) -> bool {
+        match self {
+            LineStyle::Solid => true,
+            LineStyle::Dashed(on, off) => on + off == 0 || n % (on + off) < on,
+            LineStyle::Pattern(bits) => bits & (1 << (n % 16)) != 0,
+        }
+    }
+}
+
+
+// Mutable access to everything a target stores for each pixel.
+pub struct Buffers<'a> {
+    pub pixels: &'a mut [Colour],
+    pub drawn: &'a mut [bool],
+    pub depth: &'a mut [f64],
+    pub ids: &'a mut [u32],
+    pub stats: &'a mut FrameStats,
+}
+
+pub trait Target: Sized {
+    // The size of the whole screen. Lines are clipped to this rather than to bounds so that they
+    // come out the same however the screen is split up.
+    fn viewport(&self) -> (usize, usize);
+
+    // The part of the screen that can actually be drawn to.
+    fn bounds(&self) -> Rect;
+
+    // Where pixel (x, y) of the screen is in the buffers. It will always be inside bounds.
+    fn index(&self, x: usize, y: usize) -> usize;
+
+    fn buffers(&mut self) -> Buffers<'_>;
+
+    fn draw_point(&mut self, x: usize, y: usize, colour: Colour) {
+        let rect = self.bounds();
+
+        if x < rect.0 || y < rect.1 || x >= rect.2 || y >= rect.3 {
+            return;
+        }
+
+        let i = self.index(x, y);
+        let buffers = self.buffers();
+
+        buffers.stats.pixels_shaded += 1;
+
+        if buffers.drawn[i] {
+            buffers.stats.overdraw += 1;
+        }
+
+        buffers.pixels[i] = colour;
+        buffers.drawn[i] = true;
+    }
+
+    // Blend a colour into the pixel at (x, y) if it passes the depth test. coverage is how much of
+    // the pixel the colour covers, so 1.0 replaces whatever was there. Only fully opaque writes
+    // update the depth buffer, so antialiased edges don't hide what's behind them. A depth that
+    // isn't finite means there isn't one: the colour goes on top and the depth buffer is left
+    // alone. Returns true if the depth buffer was updated.
+    fn blend_point(&mut self, x: i32, y: i32, depth: f64, colour: &Colour, coverage: f64) -> bool {
+        let rect = self.bounds();
+
+        if x < rect.0 as i32 || y < rect.1 as i32 || x >= rect.2 as i32 || y >= rect.3 as i32 || coverage <= 0.0 {
+            return false;
+        }
+
+        let i = self.index(x as usize, y as usize);
+        let buffers = self.buffers();
+
+        let tested = depth.is_finite();
+
+        if tested && depth > buffers.depth[i] {
+            return false;
+        }
+
+        let blended = colour.over(&buffers.pixels[i], coverage);
+        self.draw_point(x as usize, y as usize, blended);
+
+        if tested && coverage >= 1.0 && colour.to_rgba().3 >= 1.0 {
+            self.buffers().depth[i] = depth;
+            return true;
+        }
+
+        false
+    }
+
+    #[allow(dead_code)]
+    fn draw_line_fast(&mut self, p1: (i32, i32), p2: (i32, i32), colour: Colour) {
+        self.draw_line_styled(p1, p2, colour, LineStyle::Solid);
+    }
+
+    fn draw_line_styled(&mut self, p1: (i32, i32), p2: (i32, i32), colour: Colour, style: LineStyle) {
+        // Implementing Bresenham's Line Algorithm:
+        // This is fast but provides no antialiasing. Points are (x, y) and can be anywhere, the
+        // line is clipped to the screen first and both endpoints are drawn.
+        let (c1, c2) = match self.clip_line(p1, p2) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+
+        // How far along the line we are when we get to the first on-screen pixel, so that dashes
+        // don't shift about when one end of the line goes off the edge.
+        let mut step = cmp::max((c1.0 - p1.0).abs(), (c1.1 - p1.1).abs()) as usize;
+
+        let dx = (c2.0 - c1.0).abs();
+        let dy = -(c2.1 - c1.1).abs();
+        let sx = if c1.0 < c2.0 { 1 } else { -1 };
+        let sy = if c1.1 < c2.1 { 1 } else { -1 };
+
+        let (mut x, mut y) = c1;
+        let mut err = dx + dy;
+
+        loop {
+            if style.is_on(step) {
+                self.draw_point(x as usize, y as usize, colour.clone());
+            }
+
+            if x == c2.0 && y == c2.1 {
+                break;
+            }
+
+            let e2 = 2 * err;
+
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+
+            step += 1;
+        }
+    }
+
+    // Liang-Barsky line clipping against the screen. Returns the part of the line from p1 to p2
+    // that is on screen, or None if none of it is.
+    fn clip_line(&self, p1: (i32, i32), p2: (i32, i32)) -> Option<((i32, i32), (i32, i32))> {
+        let (width, height) = self.viewport();
+        let max = (width as f64 - 1.0, height as f64 - 1.0);
+        let (x0, y0) = (p1.0 as f64, p1.1 as f64);
+        let (dx, dy) = (p2.0 as f64 - x0, p2.1 as f64 - y0);
+
+        let (t0, t1) = clip_range((x0, y0), (dx, dy), (0.0, 0.0), max)?;
+
+        // Only move the endpoints that actually needed clipping so the rest stay exact.
+        let c1 = if t0 > 0.0 { ((x0 + t0 * dx).round() as i32, (y0 + t0 * dy).round() as i32) } else { p1 };
+        let c2 = if t1 < 1.0 { ((x0 + t1 * dx).round() as i32, (y0 + t1 * dy).round() as i32) } else { p2 };
+
+        Some((c1, c2))
+    }
+
+    #[allow(dead_code)]
+    fn draw_line_antialiased(&mut self, p1: (f64, f64), p2: (f64, f64), colour: Colour) {
+        // Without any depth the line goes on top of everything, and doesn't touch the depth buffer.
+        self.draw_line_antialiased_depth((p1.0, p1.1, f64::NAN), (p2.0, p2.1, f64::NAN), colour);
+    }
+
+    fn draw_line_antialiased_depth(&mut self, p1: (f64, f64, f64), p2: (f64, f64, f64), colour: Colour) {
+        // Implementing Wu's Line Algorithm:
+        // This is slow but antialiased
+        // The reason this takes f64 input and not integers like draw_line_fast is because this can
+        // give you a line that is not necessarily drawn *from* one pixel to another. Instead since
+        // it is antialiased it can give you something that better approximates a line from a float
+        // value instead of just integers.
+        // Each pixel's coverage is used to blend the colour over what's already there, and the
+        // depth (the third value of each point) is tested against the depth buffer.
+        if ![p1.0, p1.1, p2.0, p2.1].iter().all(|v| v.is_finite()) {
+            return;
+        }
+
+        // Cut the line down to the screen first, with a pixel of slack on each side since the
+        // antialiasing spills over into neighbouring pixels.
+        let (dx, dy, dz) = (p2.0 - p1.0, p2.1 - p1.1, p2.2 - p1.2);
+        let (width, height) = self.viewport();
+        let max = (width as f64, height as f64);
+
+        let (t0, t1) = match clip_range((p1.0, p1.1), (dx, dy), (-1.0, -1.0), max) {
+            Some(t) => t,
+            None => return,
+        };
+
+        let (mut x0, mut y0, mut z0) = (p1.0 + t0*dx, p1.1 + t0*dy, p1.2 + t0*dz);
+        let (mut x1, mut y1, mut z1) = (p1.0 + t1*dx, p1.1 + t1*dy, p1.2 + t1*dz);
+
+        // Steep lines are drawn with x and y swapped, so that we always step along the long axis.
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+
+        if steep {
+            std::mem::swap(&mut x0, &mut y0);
+            std::mem::swap(&mut x1, &mut y1);
+        }
+
+        if x0 > x1 {
+            std::mem::swap(&mut x0, &mut x1);
+            std::mem::swap(&mut y0, &mut y1);
+            std::mem::swap(&mut z0, &mut z1);
+        }
+
+        let dx = x1 - x0;
+        let dy = y1 - y0;
+        let mut gradient = 1f64;
+
+        if dx != 0.0 {
+            gradient = dy/dx;
+        }
+
+        let depth_at = |x: f64| if dx != 0.0 { z0 + (z1 - z0) * ((x - x0) / dx) } else { z0 };
+        let fpart = |v: f64| v - v.floor();
+
+        // Plots (x, y) in the swapped coordinates.
+        let plot = |target: &mut Self, x: f64, y: f64, coverage: f64| {
+            let z = depth_at(x);
+
+            if steep {
+                target.blend_point(y as i32, x as i32, z, &colour, coverage);
+            }
+
+            else {
+                target.blend_point(x as i32, y as i32, z, &colour, coverage);
+            }
+        };
+
+        // First endpoint. If you haven't realised by now I'm pretty much just reading this off
+        // Wikipedia. Donate $3!
+        let xend = x0.round();
+        let yend = y0 + gradient * (xend-x0);
+
+        let xgap = 1.0 - fpart(x0+0.5);
+        let xpxl1 = xend;
+        let ypxl1 = yend.floor();
+
+        plot(self, xpxl1, ypxl1, (1.0 - fpart(yend)) * xgap);
+        plot(self, xpxl1, ypxl1 + 1.0, fpart(yend) * xgap);
+
+        let mut intery = yend + gradient;
+
+        // Second endpoint. Same thing.
+        let xend = x1.round();
+        let yend = y1 + gradient * (xend-x1);
+
+        let xgap = fpart(x1+0.5);
+        let xpxl2 = xend;
+        let ypxl2 = yend.floor();
+
+        plot(self, xpxl2, ypxl2, (1.0 - fpart(yend)) * xgap);
+        plot(self, xpxl2, ypxl2 + 1.0, fpart(yend) * xgap);
+
+        let mut x = xpxl1 + 1.0;
+
+        while x < xpxl2 {
+            plot(self, x, intery.floor(), 1.0 - fpart(intery));
+            plot(self, x, intery.floor() + 1.0, fpart(intery));
+
+            intery += gradient;
+            x += 1.0;
+        }
+    }
+
+    #[allow(dead_code)]
+    fn draw_triangle(&mut self, p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), colour: Colour, antialiased: bool) {
+        // First, draw the lines between each vertex.
+        if antialiased {
+            self.draw_line_antialiased(p1, p2, colour.clone());
+            self.draw_line_antialiased(p2, p3, colour.clone());
+            self.draw_line_antialiased(p3, p1, colour.clone());
+            self.fill_triangle(p1, p2, p3, colour.clone());
+        }
+
+        else {
+            self.draw_wireframe_triangle(p1, p2, p3, colour.clone(), LineStyle::Solid);
+            self.fill_triangle(p1, p2, p3, colour.clone());
+        }
+    }
+
+    // Just the edges of a triangle, no filling in.
+    fn draw_wireframe_triangle(&mut self, p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), colour: Colour, style: LineStyle) {
+        let round = |p: (f64, f64)| (p.0.round() as i32, p.1.round() as i32);
+
+        self.draw_line_styled(round(p1), round(p2), colour.clone(), style);
+        self.draw_line_styled(round(p2), round(p3), colour.clone(), style);
+        self.draw_line_styled(round(p3), round(p1), colour, style);
+    }
+
+    #[allow(dead_code)]
+    fn fill_triangle(&mut self, p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), colour: Colour) {
+        let bounds = self.bounds();
+
+        rasterise_triangle([p1, p2, p3], bounds, |x, y, _| self.draw_point(x, y, colour.clone()));
+    }
+
+    // Fill a triangle, interpolating the depth across it and testing each pixel against the depth
+    // buffer. Wherever it ends up in front, id is written to the ID buffer.
+    fn fill_triangle_depth(&mut self, points: [(f64, f64); 3], depths: [f64; 3], colour: &Colour, id: u32) {
+        let bounds = self.bounds();
+
+        rasterise_triangle(points, bounds, |x, y, b| {
+            let z = b[0]*depths[0] + b[1]*depths[1] + b[2]*depths[2];
+
+            if self.blend_point(x as i32, y as i32, z, colour, 1.0) {
+                let i = self.index(x, y);
+                self.buffers().ids[i] = id;
+            }
+        });
+    }
+
+    // Fill a triangle, asking shade for the colour of every pixel that passes the depth test. It's
+    // given the pixel, its depth and the perspective correct weight of each of the three points,
+    // which needs how far away (clip space w) each of them is. Returning None leaves the pixel
+    // alone.
+    fn fill_triangle_shaded<F>(&mut self, points: [(f64, f64); 3], depths: [f64; 3], w: [f64; 3], id: u32, mut shade: F)
+    where
+        F: FnMut(usize, usize, f64, [f64; 3]) -> Option<Colour>,
+    {
+        let bounds = self.bounds();
+
+        rasterise_triangle(points, bounds, |x, y, b| {
+            let z = b[0]*depths[0] + b[1]*depths[1] + b[2]*depths[2];
+            let i = self.index(x, y);
+
+            // Checked before shading as well as when it's drawn so hidden pixels aren't shaded.
+            if z > self.buffers().depth[i] {
+                return;
+            }
+
+            // The barycentric coordinates are linear on the screen but not in the world, since
+            // things further away are squashed up. Dividing by w undoes that.
+            let p = [b[0] / w[0], b[1] / w[1], b[2] / w[2]];
+            let total = p[0] + p[1] + p[2];
+
+            if total == 0.0 || !total.is_finite() {
+                return;
+            }
+
+            if let Some(colour) = shade(x, y, z, [p[0] / total, p[1] / total, p[2] / total]) {
+                if self.blend_point(x as i32, y as i32, z, &colour, 1.0) {
+                    self.buffers().ids[i] = id;
+                }
+            }
+        });
+    }
+
+    // A triangle out of the pipeline, with antialiased edges if asked for.
+    fn draw_screen_triangle(&mut self, t: &ScreenTriangle, antialiased: bool) {
+        if antialiased {
+            for i in 0..3 {
+                let (a, b) = (i, (i + 1) % 3);
+
+                self.draw_line_antialiased_depth(
+                    (t.points[a].0, t.points[a].1, t.depths[a]),
+                    (t.points[b].0, t.points[b].1, t.depths[b]),
+                    t.colour.clone(),
+                );
+            }
+        }
+
+        self.fill_triangle_depth(t.points, t.depths, &t.colour, t.id);
+    }
+}
+
+// A rectangle of the screen with its own copy of the buffers, so that it can be drawn into on its
+// own thread and copied back into the device afterwards.
+pub struct Tile {
+    pub viewport: (usize, usize),
+    pub rect: Rect,
+    pub pixels: Vec<Colour>,
+    pub drawn: Vec<bool>,
+    pub depth: Vec<f64>,
+    pub ids: Vec<u32>,
+    pub stats: FrameStats,
+}
+
+impl Target for Tile {
+    fn viewport(&self) -> (usize, usize) {
+        self.viewport
+    }
+
+    fn bounds(&self) -> Rect {
+        self.rect
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y - self.rect.1) * (self.rect.2 - self.rect.0) + (x - self.rect.0)
+    }
+
+    fn buffers(&mut self) -> Buffers<'_> {
+        Buffers {
+            pixels: &mut self.pixels,
+            drawn: &mut self.drawn,
+            depth: &mut self.depth,
+            ids: &mut self.ids,
+            stats: &mut self.stats,
+        }
+    }
+}
+
+// The Liang-Barsky part: for the line p + t*d with t from 0 to 1, find the range of t that's
+// inside the box from min to max.
+pub fn clip_range(p: (f64, f64), d: (f64, f64), min: (f64, f64), max: (f64, f64)) -> Option<(f64, f64)> {
+    let (x0, y0) = p;
+    let (dx, dy) = d;
+
+    let mut t0 = 0f64;
+    let mut t1 = 1f64;
+
+    // Each pair is (p, q) for one edge of the screen: left, right, top, bottom.
+    let edges = [(-dx, x0 - min.0), (dx, max.0 - x0), (-dy, y0 - min.1), (dy, max.1 - y0)];
+
+    for (p, q) in edges.iter() {
+        if *p == 0.0 {
+            // Parallel to this edge, so either entirely inside or entirely outside it.
+            if *q < 0.0 {
+                return None;
+            }
+        }
+
+        else {
+            let t = q / p;
+
+            if *p < 0.0 {
+                t0 = t0.max(t);
+            }
+
+            else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+
+    if t0 > t1 {
+        return None;
+    }
+
+    Some((t0, t1))
+}
+
@@ -0,0 +1,99 @@
+// Toon (cel) shading. The lighting is cut down to a few flat bands, and outlines are drawn around
+// the edges of things with line characters that follow the way the edge goes. An edge is anywhere
+// a cell's neighbour is background, much further away (a silhouette against something behind) or
+// facing a very different way (a crease).
+
+use crate::device::*;
+use crate::glyphs::*;
+use crate::render_objects::*;
+use crate::structures::*;
+
+// Neighbours of a cell, as (dx, dy).
+const NEIGHBOURS: [(i64, i64); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+// The same colour, but with its brightness moved to the middle of whichever of bands it's in.
+pub fn quantise(colour: &Colour, bands: usize) -> Colour {
+    let bands = bands.max(1) as f64;
+    let intensity = colour.intensity();
+
+    if intensity <= 0.0 {
+        return colour.clone();
+    }
+
+    let banded = (((intensity * bands).floor() + 0.5) / bands).min(1.0);
+
+    match colour {
+        Colour::Grey(_) => Colour::Grey(banded),
+        Colour::Rgba(..) => colour.scaled(banded / intensity),
+    }
+}
+
+impl Device {
+    // Bands the lighting and draws the outlines. Uses the ID buffer and id_shading, as rasterise
+    // makes them, to tell where the faces are and which way they point.
+    pub fn toon(&mut self) {
+        let (width, height) = self.dimensions;
+        let shading = &self.id_shading;
+        let sharp = self.crease_angle.cos();
+
+        for i in 0..width * height {
+            if self.ids[i] != 0 {
+                self.pixels[i] = quantise(&self.pixels[i], self.toon_bands);
+            }
+        }
+
+        let distance: Vec<f64> = self.depth.iter().map(|z| self.camera.linear_depth(*z)).collect();
+
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let id = self.ids[i];
+
+                if id == 0 {
+                    continue;
+                }
+
+                let normal = shading[id as usize - 1].1;
+                let mut towards = (0.0, 0.0);
+                let mut edge = false;
+
+                for (dx, dy) in NEIGHBOURS {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+
+                    // Off the screen isn't an edge, the thing just carries on.
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+
+                    let j = ny as usize * width + nx as usize;
+                    let other = self.ids[j];
+
+                    // Background, or something much further away. Only the nearer side of a
+                    // jump in depth gets the line, so it hugs the outside of whatever's in front.
+                    let silhouette = other == 0 || distance[j] - distance[i] > self.outline_depth * distance[i];
+                    let crease = other != 0 && other != id && Vector::dot_product(&normal, &shading[other as usize - 1].1) < sharp;
+
+                    if silhouette {
+                        edge = true;
+                    }
+
+                    // Both sides of a crease are different from each other, so only draw it on one
+                    // of them to keep it one cell thick.
+                    else if crease {
+                        edge |= dy > 0 || (dy == 0 && dx > 0);
+                    }
+
+                    else {
+                        continue;
+                    }
+
+                    towards = (towards.0 + dx as f64, towards.1 + dy as f64);
+                }
+
+                if edge {
+                    self.glyphs[i] = Some(edge_glyph(towards));
+                }
+            }
+        }
+    }
+}